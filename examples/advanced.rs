@@ -8,7 +8,7 @@ fn main() -> Result<()> {
     // Init a monotree instance:
     // manually select a db and a hasher as your preference
     // Monotree::<DATABASE, HASHER>::new(DB_PATH)
-    // where DATABASE = {MemoryDB, RocksDB, Sled}
+    // where DATABASE = {MemoryDB, RocksDB, Sled, CachedFlatFile}
     //         HASHER = {Blake3, Blake2s, Blake2b, Sha2, Sha3}
     let mut tree = Monotree::<RocksDB, Blake2b>::new("/tmp/monotree");
 
@@ -19,13 +19,25 @@ impl<'a> Bits<'a> {
         }
     }
 
+    /// The bits out of the range, in the first and the last byte of the path,
+    /// are zeroed out, so that the bytes are given by the bits in the range alone
+    /// and not by the key the path was taken from.
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
-        Ok([
-            &self.range.start.to_be_bytes(),
-            &self.range.end.to_be_bytes(),
-            &self.path[..],
-        ]
-        .concat())
+        let (start, end) = (self.range.start, self.range.end);
+        let n = nbytes_across(start, end) as usize;
+        if start >= 8 || start >= end || self.path.len() < n {
+            return Err(Error::CorruptNode);
+        }
+        let mut path = self.path[..n].to_vec();
+        path[0] &= 0xff >> start;
+        if end % 8 != 0 {
+            path[n - 1] &= 0xff << (8 - end % 8);
+        }
+        Ok([&start.to_be_bytes(), &end.to_be_bytes(), &path[..]].concat())
+    }
+
+    pub fn to_bits(&self) -> Vec<bool> {
+        bytes_to_slicebit(self.path, &self.range)
     }
 
     pub fn first(&self) -> bool {
@@ -59,3 +71,46 @@ impl<'a> Bits<'a> {
         len_lcp(&a.path, &a.range, &b.path, &b.range)
     }
 }
+
+impl<'a> From<&Bits<'a>> for OwnedBits {
+    fn from(bits: &Bits<'a>) -> Self {
+        OwnedBits {
+            path: bits.path.to_vec(),
+            range: bits.range.clone(),
+        }
+    }
+}
+
+impl OwnedBits {
+    pub fn as_bits(&self) -> Bits<'_> {
+        Bits {
+            path: &self.path,
+            range: self.range.clone(),
+        }
+    }
+
+    /// The bits of a unit followed by those of the unit under it,
+    /// as a unit takes over the only unit left in the node it points to.
+    pub(crate) fn join(upper: &Bits, lower: &Bits) -> Result<Self> {
+        let start = upper.range.start;
+        let end = (start as usize) + (upper.len() as usize) + (lower.len() as usize);
+        if end > BitsLen::max_value() as usize {
+            return Err(Error::CorruptNode);
+        }
+        let mut bits = vec![false; start as usize];
+        bits.extend(upper.to_bits());
+        bits.extend(lower.to_bits());
+        let path = bits
+            .chunks(8)
+            .map(|byte| {
+                byte.iter()
+                    .enumerate()
+                    .fold(0u8, |acc, (i, &bit)| acc | (bit as u8) << (7 - i))
+            })
+            .collect();
+        Ok(OwnedBits {
+            path,
+            range: start..end as BitsLen,
+        })
+    }
+}
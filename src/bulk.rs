@@ -0,0 +1,380 @@
+//! Bulk update: the sorted keys go down the tree all together, splitting by their bits
+//! wherever the tree branches, and each node on the way is built once from the subtrees below.
+//! The subtrees are disjoint, so that they are built in parallel with the `rayon` feature.
+//!
+//! The nodes touched are read from the database beforehand, and the nodes built are
+//! written afterwards, so that the build itself works in memory only.
+//! With insertions only, the root is byte-for-byte the one given by inserting the keys
+//! one by one in order, since each step below follows what `Monotree::put()` would do
+//! with the same keys. Likewise, removals of keys in the tree follow `Monotree::delete_key()`,
+//! joining the only unit left in a node with the unit pointing to the node.
+//! When both are mixed, the removals take effect before the insertions.
+use crate::utils::*;
+use crate::*;
+use hashbrown::HashMap;
+use std::cmp;
+
+/// Subtrees with fewer entries than this are built in the current thread
+#[cfg(feature = "rayon")]
+const FORK_MIN_LEN: usize = 1 << 6;
+
+/// Bits of an encoded key from the depth being built,
+/// and the leaf to put under it or `None` to remove it
+type Entry<'a> = (Bits<'a>, Option<&'a Hash>);
+
+/// Units built, with the bits taken from either the nodes read or the keys
+type Built = (Hash, OwnedBits);
+
+impl<D, H> Monotree<D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    /// Insert or remove the entries of keys sorted all at once, yielding a new root.
+    /// Of the entries with the same key, only the last in order of keys is taken.
+    pub(crate) fn update_sorted(
+        &mut self,
+        root: Option<&Hash>,
+        keys: &[Vec<u8>],
+        leaves: &[Option<&Hash>],
+    ) -> Result<Option<Hash>> {
+        if keys.is_empty() {
+            return Ok(root.cloned());
+        }
+        let entries: Vec<Entry> = (0..keys.len())
+            .filter(|&i| i + 1 == keys.len() || keys[i] != keys[i + 1])
+            .map(|i| (Bits::new(&keys[i]), leaves[i]))
+            .collect();
+        let mut nodes: HashMap<Hash, Vec<u8>> = HashMap::new();
+        if let Some(root) = root {
+            for (bits, _) in entries.iter() {
+                self.prefetch(root, bits.clone(), &mut nodes)?;
+            }
+        }
+        let mut build: Build<H> = Build::new(&nodes);
+        let root = match root {
+            Some(root) => build.node(root, &entries)?,
+            None => build.root(&entries)?,
+        };
+        self.db.init_batch()?;
+        for (hash, bytes) in build.writes {
+            self.db.put(&hash, bytes)?;
+        }
+        self.db.finish_batch()?;
+        Ok(root)
+    }
+
+    /// Read the nodes on the path to a key, as far as the key goes down the tree
+    fn prefetch(
+        &self,
+        root: &Hash,
+        mut bits: Bits,
+        nodes: &mut HashMap<Hash, Vec<u8>>,
+    ) -> Result<()> {
+        let mut hash = *root;
+        loop {
+            if !nodes.contains_key(&hash) {
+                nodes.insert(hash, self.get_node(&hash)?);
+            }
+            let (cell, _) = Node::cells_from_bytes(&nodes[&hash], bits.first())?;
+            let unit = cell.as_ref().ok_or(Error::CorruptNode)?;
+            let n = Bits::len_common_bits(&unit.bits, &bits);
+            if n == bits.len() || n < unit.bits.len() {
+                return Ok(());
+            }
+            hash = slice_to_hash(unit.hash);
+            bits = bits.shift(n, false);
+        }
+    }
+}
+
+/// Builder of the nodes over the entries, writing to memory
+struct Build<'a, H> {
+    hasher: H,
+    nodes: &'a HashMap<Hash, Vec<u8>>,
+    writes: Vec<(Hash, Vec<u8>)>,
+}
+
+impl<'a, H: Hasher> Build<'a, H> {
+    fn new(nodes: &'a HashMap<Hash, Vec<u8>>) -> Self {
+        Build {
+            hasher: H::new(),
+            nodes,
+            writes: Vec::new(),
+        }
+    }
+
+    fn put_bytes(&mut self, bytes: Vec<u8>) -> Hash {
+        let hash = self.hasher.digest(&bytes);
+        self.writes.push((hash, bytes));
+        hash
+    }
+
+    /// Put a node of the units built, or nothing if there are no units left
+    fn put_units(&mut self, lc: Option<Built>, rc: Option<Built>) -> Result<Option<Hash>> {
+        match (&lc, &rc) {
+            (None, None) => Ok(None),
+            _ => {
+                let bytes = Node::new(unit_of(&lc), unit_of(&rc)).to_bytes()?;
+                Ok(Some(self.put_bytes(bytes)))
+            }
+        }
+    }
+
+    /// Put the entries under the root read before.
+    /// The root is gone if all under it are removed,
+    /// and left as it is if none of the entries changes it.
+    fn node(&mut self, hash: &[u8], entries: &[Entry<'a>]) -> Result<Option<Hash>> {
+        match self.children(hash, entries)? {
+            (None, None) => Ok(None),
+            (lc, rc) => Ok(Some(self.rebuild(hash, lc, rc)?)),
+        }
+    }
+
+    /// Put the entries under the units of a node read before, giving the units left
+    fn children(
+        &mut self,
+        hash: &[u8],
+        entries: &[Entry<'a>],
+    ) -> Result<(Option<Built>, Option<Built>)> {
+        let nodes = self.nodes;
+        let bytes = nodes
+            .get(hash)
+            .ok_or_else(|| Error::MissingNode(slice_to_hash(hash)))?;
+        let (left, right) = split_by_side(entries);
+        let (lc, rc) = match Node::from_bytes(bytes)? {
+            Node::Hard(Some(lu), Some(ru)) => self.fork(
+                entries.len(),
+                |build| build.unit_or_none(Some(lu), left),
+                |build| build.unit_or_none(Some(ru), right),
+            )?,
+            Node::Soft(Some(unit)) => {
+                let (same, other) = if unit.bits.first() {
+                    (right, left)
+                } else {
+                    (left, right)
+                };
+                self.fork(
+                    entries.len(),
+                    |build| build.unit_or_none(Some(unit), same),
+                    |build| build.unit_or_none(None, other),
+                )?
+            }
+            _ => return Err(Error::CorruptNode),
+        };
+        Ok((lc, rc))
+    }
+
+    /// Put a node read before with the units built, unless it is left as it is
+    fn rebuild(&mut self, hash: &[u8], lc: Option<Built>, rc: Option<Built>) -> Result<Hash> {
+        let built = Node::new(unit_of(&lc), unit_of(&rc)).to_bytes()?;
+        match Some(&built) == self.nodes.get(hash) {
+            true => Ok(slice_to_hash(hash)),
+            false => Ok(self.put_bytes(built)),
+        }
+    }
+
+    /// Put the entries under no root
+    fn root(&mut self, entries: &[Entry<'a>]) -> Result<Option<Hash>> {
+        let (left, right) = split_by_side(entries);
+        let (lc, rc) = self.fork(
+            entries.len(),
+            |build| build.unit_or_none(None, left),
+            |build| build.unit_or_none(None, right),
+        )?;
+        self.put_units(lc, rc)
+    }
+
+    /// Put the entries under a unit, or under nothing if no unit is given.
+    /// The unit is left as it is if there are no entries.
+    fn unit_or_none(
+        &mut self,
+        unit: Option<Unit<'a>>,
+        entries: &[Entry<'a>],
+    ) -> Result<Option<Built>> {
+        match (unit, entries.is_empty()) {
+            (None, true) => Ok(None),
+            (Some(unit), true) => Ok(Some(built_of(&unit))),
+            (Some(unit), false) => self.unit(unit, entries),
+            (None, false) => self.fresh(entries),
+        }
+    }
+
+    /// Put the entries under a unit whose first bit is the same as theirs:
+    /// replace or remove the leaf, go down through the unit, or split the unit
+    /// where the first entry in order of the bits parts from it, as `Monotree::put()` does.
+    /// Removals of keys parting from the unit have no effect, as the keys are not in the tree.
+    fn unit(&mut self, unit: Unit<'a>, entries: &[Entry<'a>]) -> Result<Option<Built>> {
+        let entries: Vec<Entry> = entries
+            .iter()
+            .filter(|(bits, leaf)| {
+                leaf.is_some() || Bits::len_common_bits(&unit.bits, bits) == unit.bits.len()
+            })
+            .cloned()
+            .collect();
+        if entries.is_empty() {
+            return Ok(Some(built_of(&unit)));
+        }
+        let (first, last) = (&entries[0], &entries[entries.len() - 1]);
+        let n = min!(
+            Bits::len_common_bits(&unit.bits, &first.0),
+            Bits::len_common_bits(&unit.bits, &last.0)
+        );
+        if n == 0 {
+            return Err(Error::CorruptNode);
+        }
+        if n == unit.bits.len() {
+            if n == first.0.len() && n == last.0.len() {
+                return Ok(last.1.map(|leaf| (*leaf, OwnedBits::from(&last.0))));
+            }
+            return match self.children(unit.hash, &shift(&entries, n)?)? {
+                (None, None) => Ok(None),
+                (Some((hash, bits)), None) | (None, Some((hash, bits))) => {
+                    Ok(Some((hash, OwnedBits::join(&unit.bits, &bits.as_bits())?)))
+                }
+                (lc, rc) => {
+                    let hash = self.rebuild(unit.hash, lc, rc)?;
+                    Ok(Some((hash, OwnedBits::from(&unit.bits))))
+                }
+            };
+        }
+        let lower = Unit {
+            hash: unit.hash,
+            bits: unit.bits.shift(n, false),
+        };
+        match self.split(Some(lower), &shift(&entries, n)?)? {
+            Some(hash) => Ok(Some((hash, OwnedBits::from(&unit.bits.shift(n, true))))),
+            None => self.fresh(&entries),
+        }
+    }
+
+    /// Put the entries under nothing: the bits common to all of them make a unit
+    /// taken from the first entry, as it is the first inserted.
+    /// Removals have no effect here, as the keys are not in the tree.
+    fn fresh(&mut self, entries: &[Entry<'a>]) -> Result<Option<Built>> {
+        let entries: Vec<Entry> = entries
+            .iter()
+            .filter(|(_, leaf)| leaf.is_some())
+            .cloned()
+            .collect();
+        if entries.is_empty() {
+            return Ok(None);
+        }
+        let (first, last) = (&entries[0], &entries[entries.len() - 1]);
+        let n = Bits::len_common_bits(&first.0, &last.0);
+        if n == first.0.len() {
+            return Ok(last.1.map(|leaf| (*leaf, OwnedBits::from(&last.0))));
+        }
+        let hash = self.split(None, &shift(&entries, n)?)?;
+        Ok(hash.map(|hash| (hash, OwnedBits::from(&first.0.shift(n, true)))))
+    }
+
+    /// Build a `Node::Hard` where the entries part from each other or from the unit given.
+    /// Returns `None` if the removals under the unit take it away, leaving nothing to split.
+    fn split(&mut self, unit: Option<Unit<'a>>, entries: &[Entry<'a>]) -> Result<Option<Hash>> {
+        let (left, right) = split_by_side(entries);
+        let (sc, oc) = match unit {
+            None => self.fork(
+                entries.len(),
+                |build| build.unit_or_none(None, left),
+                |build| build.unit_or_none(None, right),
+            )?,
+            Some(unit) => {
+                let (same, other) = if unit.bits.first() {
+                    (right, left)
+                } else {
+                    (left, right)
+                };
+                if same.iter().any(|(_, leaf)| leaf.is_none()) {
+                    match self.unit(unit, same)? {
+                        None => return Ok(None),
+                        sc => (sc, self.unit_or_none(None, other)?),
+                    }
+                } else {
+                    self.fork(
+                        entries.len(),
+                        |build| build.unit_or_none(Some(unit), same),
+                        |build| build.unit_or_none(None, other),
+                    )?
+                }
+            }
+        };
+        match (&sc, &oc) {
+            (Some(_), Some(_)) => self.put_units(sc, oc),
+            _ => Err(Error::CorruptNode),
+        }
+    }
+
+    /// Build two subtrees one after the other
+    #[cfg(not(feature = "rayon"))]
+    fn fork<A, B, RA, RB>(&mut self, _len: usize, a: A, b: B) -> Result<(RA, RB)>
+    where
+        A: FnOnce(&mut Self) -> Result<RA> + Send,
+        B: FnOnce(&mut Self) -> Result<RB> + Send,
+        RA: Send,
+        RB: Send,
+    {
+        Ok((a(self)?, b(self)?))
+    }
+
+    /// Build two subtrees in parallel, each with a builder of its own,
+    /// unless they are too small to be worth it
+    #[cfg(feature = "rayon")]
+    fn fork<A, B, RA, RB>(&mut self, len: usize, a: A, b: B) -> Result<(RA, RB)>
+    where
+        A: FnOnce(&mut Self) -> Result<RA> + Send,
+        B: FnOnce(&mut Self) -> Result<RB> + Send,
+        RA: Send,
+        RB: Send,
+    {
+        if len < FORK_MIN_LEN {
+            return Ok((a(self)?, b(self)?));
+        }
+        let nodes = self.nodes;
+        let (ra, rb) = rayon::join(
+            || {
+                let mut build = Build::new(nodes);
+                a(&mut build).map(|ra| (ra, build.writes))
+            },
+            || {
+                let mut build = Build::new(nodes);
+                b(&mut build).map(|rb| (rb, build.writes))
+            },
+        );
+        let ((ra, wa), (rb, wb)) = (ra?, rb?);
+        self.writes.extend(wa);
+        self.writes.extend(wb);
+        Ok((ra, rb))
+    }
+}
+
+fn unit_of(built: &Option<Built>) -> Option<Unit<'_>> {
+    built.as_ref().map(|(hash, bits)| Unit {
+        hash,
+        bits: bits.as_bits(),
+    })
+}
+
+fn built_of(unit: &Unit) -> Built {
+    (slice_to_hash(unit.hash), OwnedBits::from(&unit.bits))
+}
+
+/// Split the entries sorted into those going to the left and those going to the right
+fn split_by_side<'e, 'a>(entries: &'e [Entry<'a>]) -> (&'e [Entry<'a>], &'e [Entry<'a>]) {
+    let i = entries
+        .iter()
+        .position(|(bits, _)| bits.first())
+        .unwrap_or(entries.len());
+    entries.split_at(i)
+}
+
+/// Consume the first `n` bits of the entries, all of which must have more than that
+fn shift<'a>(entries: &[Entry<'a>], n: BitsLen) -> Result<Vec<Entry<'a>>> {
+    entries
+        .iter()
+        .map(|(bits, leaf)| match bits.len() > n {
+            true => Ok((bits.shift(n, false), *leaf)),
+            false => Err(Error::CorruptNode),
+        })
+        .collect()
+}
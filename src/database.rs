@@ -1,56 +1,284 @@
 use crate::*;
 use hashbrown::{HashMap, HashSet};
-use rocksdb::{WriteBatch, DB};
-use std::path::Path;
+#[cfg(feature = "lmdb")]
+use lmdb::{Cursor, Environment, Transaction, WriteFlags};
+use rocksdb::{IteratorMode, Options, WriteBatch, DB};
+#[cfg(feature = "rusqlite")]
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use utils::*;
 
-struct MemCache {
-    set: HashSet<Hash>,
-    map: HashMap<Hash, Vec<u8>>,
+/// Default size of the node caches, in bytes of keys and values
+pub const DEFAULT_CACHE_SIZE: usize = 1 << 26;
+
+/// Default size of the segment files of `FlatFile`, in bytes,
+/// beyond which appending goes on to a new segment
+pub const DEFAULT_SEGMENT_SIZE: u64 = 1 << 26;
+
+/// Default size of the memory map of `Lmdb`, which is as large as its database can grow.
+/// Only address space is reserved up front: the file grows with what is written.
+/// It is 64 GiB, or 1 GiB where the address space is of 32 bits.
+#[cfg(all(feature = "lmdb", target_pointer_width = "64"))]
+pub const DEFAULT_MAP_SIZE: usize = 1 << 36;
+#[cfg(all(feature = "lmdb", not(target_pointer_width = "64")))]
+pub const DEFAULT_MAP_SIZE: usize = 1 << 30;
+
+/// Hits and misses of a node cache, counted on reads
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Value cached, or `None` for a tombstone, with the tick of its last use unless it is pinned
+type Cached = (Option<Vec<u8>>, Option<u64>);
+
+/// Byte-bounded LRU cache of the values read and written.
+/// It lives across batches, so that the upper nodes, touched by every update, stay in it.
+/// The writes made in a batch are pinned until the batch is finished,
+/// as they are not readable from the database yet. Deletions are pinned as tombstones.
+/// Thus, the cache may go beyond its size while a large batch is in progress.
+struct LruCache {
+    size: usize,
+    bytes: usize,
+    tick: u64,
+    map: HashMap<Vec<u8>, Cached>,
+    order: BTreeMap<u64, Vec<u8>>,
+    pinned: Vec<Vec<u8>>,
+    batch_on: bool,
+    stats: CacheStats,
 }
 
-impl MemCache {
-    fn new() -> Self {
-        MemCache {
-            set: HashSet::new(),
-            map: HashMap::with_capacity(1 << 12),
+impl LruCache {
+    fn new(size: usize) -> Self {
+        LruCache {
+            size,
+            bytes: 0,
+            tick: 0,
+            map: HashMap::new(),
+            order: BTreeMap::new(),
+            pinned: Vec::new(),
+            batch_on: false,
+            stats: CacheStats::default(),
         }
     }
 
-    fn clear(&mut self) {
-        self.set.clear();
-        self.map.clear();
+    /// Look up a key: `Some(None)` if it is deleted in the batch in progress,
+    /// or `None` if it is not in the cache.
+    fn get(&mut self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        let tick = self.tick + 1;
+        match self.map.get_mut(key) {
+            Some((value, Some(last))) => {
+                let key = self.order.remove(last).expect("get(): cache");
+                self.order.insert(tick, key);
+                *last = tick;
+                self.tick = tick;
+                self.stats.hits += 1;
+                Some(value.clone())
+            }
+            Some((value, None)) => {
+                self.stats.hits += 1;
+                Some(value.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
     }
 
-    fn contains(&self, key: &[u8]) -> bool {
-        self.set.contains(key) || self.map.contains_key(key)
+    /// Remove an entry, returning if it was pinned
+    fn take(&mut self, key: &[u8]) -> bool {
+        match self.map.remove(key) {
+            Some((value, tick)) => {
+                self.bytes -= key.len() + value.map_or(0, |value| value.len());
+                match tick {
+                    Some(tick) => self.order.remove(&tick).is_none(),
+                    None => true,
+                }
+            }
+            None => false,
+        }
     }
 
-    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        match self.map.get(key) {
-            Some(v) => Ok(Some(v.to_owned())),
-            None => Ok(None),
+    /// Put an entry, pinned or in order of use
+    fn set(&mut self, key: &[u8], value: Option<Vec<u8>>, pin: bool) {
+        if !self.take(key) && pin {
+            self.pinned.push(key.to_vec());
         }
+        self.bytes += key.len() + value.as_ref().map_or(0, |value| value.len());
+        let tick = match pin {
+            true => None,
+            false => {
+                self.tick += 1;
+                self.order.insert(self.tick, key.to_vec());
+                Some(self.tick)
+            }
+        };
+        self.map.insert(key.to_vec(), (value, tick));
+        self.evict();
     }
 
-    fn put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
-        self.map.insert(slice_to_hash(key), value);
-        if self.set.contains(key) {
-            self.set.remove(key);
+    /// Evict the entries least recently used until the cache fits in its size
+    fn evict(&mut self) {
+        while self.bytes > self.size {
+            let tick = match self.order.keys().next() {
+                Some(tick) => *tick,
+                None => return,
+            };
+            let key = self.order.remove(&tick).expect("evict(): cache");
+            self.take(&key);
         }
-        Ok(())
     }
 
-    fn delete(&mut self, key: &[u8]) -> Result<()> {
-        self.map.remove(key);
-        self.set.insert(slice_to_hash(key));
-        Ok(())
+    /// Cache a value read from the database
+    fn insert(&mut self, key: &[u8], value: Vec<u8>) {
+        self.set(key, Some(value), false);
     }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>) {
+        let pin = self.batch_on;
+        self.set(key, Some(value), pin);
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        match self.batch_on {
+            true => self.set(key, None, true),
+            false => {
+                self.take(key);
+            }
+        }
+    }
+
+    /// Pin the writes from now on, until `commit()`.
+    /// Entries still pinned are from a batch abandoned before `commit()`,
+    /// never written to the database, so they are dropped.
+    fn begin(&mut self) {
+        for key in std::mem::take(&mut self.pinned) {
+            self.take(&key);
+        }
+        self.batch_on = true;
+    }
+
+    /// Unpin the writes of the batch, as they are in the database now
+    fn commit(&mut self) {
+        self.batch_on = false;
+        for key in std::mem::take(&mut self.pinned) {
+            match self.map.get(&key) {
+                Some((Some(value), None)) => {
+                    let value = value.to_owned();
+                    self.set(&key, Some(value), false);
+                }
+                Some((None, None)) => {
+                    self.take(&key);
+                }
+                _ => {}
+            }
+        }
+        self.evict();
+    }
+}
+
+/// Keep the keys of `HASH_LEN` bytes out of all the keys read from a backend
+fn hash_keys<'a, K, I>(keys: I) -> Keys<'a>
+where
+    K: AsRef<[u8]>,
+    I: Iterator<Item = Result<K>> + 'a,
+{
+    Box::new(keys.filter_map(|key| match key {
+        Ok(key) if key.as_ref().len() != HASH_LEN => None,
+        key => Some(key.map(|key| slice_to_hash(key.as_ref()))),
+    }))
+}
+
+/// Keys read in pages of `KEYS_PAGE_LEN` in order, each page starting after the last key read,
+/// for the backends whose cursors cannot be held beyond a transaction or a lock
+#[cfg(any(feature = "lmdb", feature = "rusqlite"))]
+struct Pages<F> {
+    read: F,
+    last: Option<Vec<u8>>,
+    page: std::vec::IntoIter<Vec<u8>>,
+    done: bool,
+}
+
+#[cfg(any(feature = "lmdb", feature = "rusqlite"))]
+const KEYS_PAGE_LEN: usize = 1 << 12;
+
+#[cfg(any(feature = "lmdb", feature = "rusqlite"))]
+impl<F: FnMut(Option<&[u8]>) -> Result<Vec<Vec<u8>>>> Pages<F> {
+    fn new(read: F) -> Self {
+        Pages {
+            read,
+            last: None,
+            page: Vec::new().into_iter(),
+            done: false,
+        }
+    }
+}
+
+#[cfg(any(feature = "lmdb", feature = "rusqlite"))]
+impl<F: FnMut(Option<&[u8]>) -> Result<Vec<Vec<u8>>>> Iterator for Pages<F> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.page.len() == 0 && !self.done {
+            match (self.read)(self.last.as_deref()) {
+                Ok(page) => {
+                    self.done = page.len() < KEYS_PAGE_LEN;
+                    self.page = page.into_iter();
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        let key = self.page.next()?;
+        self.last = Some(key.clone());
+        Some(Ok(key))
+    }
+}
+
+/// Get the hashes of the units of a node with the key bits on the path
+/// down to each unit, telling if the unit points to a leaf.
+fn children(bytes: &[u8], path: &[bool]) -> Result<Vec<(Hash, Vec<bool>)>> {
+    let (lc, rc) = Node::cells_from_bytes(bytes, false)?;
+    [lc, rc]
+        .iter()
+        .flatten()
+        .map(|unit| Ok((slice_to_hash(unit.hash), join_path(path, &unit.bits)?)))
+        .collect()
+}
+
+/// Mark the nodes and leaves under the roots given as live, as kept by `Monotree::prune()`
+/// and `FlatFile::compact()`. Leaves are marked as well to keep the values stored under them.
+/// The key bits on the path down to each unit tell those pointing to leaves from the others.
+pub(crate) fn mark(db: &impl Database, roots: &[Hash]) -> Result<HashSet<Hash>> {
+    let mut live = HashSet::new();
+    let mut stack: Vec<(Hash, Vec<bool>)> = roots.iter().map(|root| (*root, Vec::new())).collect();
+    while let Some((hash, path)) = stack.pop() {
+        if !live.insert(hash) {
+            continue;
+        }
+        let bytes = db.get(&hash)?.ok_or(Error::MissingNode(hash))?;
+        for (child, path) in children(&bytes, &path)? {
+            if is_complete_key(&path) {
+                live.insert(child);
+            } else {
+                stack.push((child, path));
+            }
+        }
+    }
+    Ok(live)
 }
 
 pub struct MemoryDB {
-    db: HashMap<Hash, Vec<u8>>,
+    db: HashMap<Vec<u8>, Vec<u8>>,
 }
 
 impl Database for MemoryDB {
@@ -58,7 +286,7 @@ impl Database for MemoryDB {
         MemoryDB { db: HashMap::new() }
     }
 
-    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         match self.db.get(key) {
             Some(v) => Ok(Some(v.to_owned())),
             None => Ok(None),
@@ -66,7 +294,7 @@ impl Database for MemoryDB {
     }
 
     fn put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
-        self.db.insert(slice_to_hash(key), value);
+        self.db.insert(key.to_vec(), value);
         Ok(())
     }
 
@@ -74,169 +302,1201 @@ impl Database for MemoryDB {
         self.db.remove(key);
         Ok(())
     }
+}
+
+impl IterableDatabase for MemoryDB {
+    fn keys(&self) -> Result<Keys<'_>> {
+        Ok(hash_keys(self.db.keys().map(Ok)))
+    }
+}
+
+/// RocksDB adapter, writing straight to the database or all at once by `write_batch()`.
+/// `RocksDB` wraps it in `BatchingDb` and `CachedDb` for batches and caching.
+pub struct RawRocksDB {
+    db: Arc<DB>,
+}
+
+impl From<rocksdb::Error> for Error {
+    fn from(err: rocksdb::Error) -> Self {
+        Error::Backend(Box::new(err))
+    }
+}
+
+impl RawRocksDB {
+    /// Open a RocksDB with the options given, instead of the default ones
+    pub fn open_with(dbpath: &str, opts: &Options) -> Result<Self> {
+        Ok(Self::from_db(DB::open(opts, Path::new(dbpath))?))
+    }
+
+    /// Use a RocksDB handle already opened
+    pub fn from_db(db: DB) -> Self {
+        RawRocksDB { db: Arc::new(db) }
+    }
+}
+
+impl Database for RawRocksDB {
+    fn new(dbpath: &str) -> Self {
+        Self::open(dbpath).expect("new(): rocksdb")
+    }
+
+    fn open(dbpath: &str) -> Result<Self> {
+        Ok(Self::from_db(DB::open_default(Path::new(dbpath))?))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?)
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        Ok(self.db.put(key, value)?)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        Ok(self.db.delete(key)?)
+    }
+
+    fn write_batch(&mut self, writes: Batch) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        for (key, value) in writes {
+            match value {
+                Some(value) => batch.put(key, value)?,
+                None => batch.delete(key)?,
+            }
+        }
+        Ok(self.db.write(batch)?)
+    }
+}
+
+impl SharedDatabase for RawRocksDB {
+    fn share(&self) -> Result<Self> {
+        Ok(RawRocksDB {
+            db: Arc::clone(&self.db),
+        })
+    }
+}
+
+impl IterableDatabase for RawRocksDB {
+    fn keys(&self) -> Result<Keys<'_>> {
+        let keys = self
+            .db
+            .iterator(IteratorMode::Start)
+            .map(|(key, _)| Ok(key));
+        Ok(hash_keys(keys))
+    }
+}
+
+/// Sled adapter, writing straight to the database or all at once by `write_batch()`.
+/// `Sled` wraps it in `BatchingDb` and `CachedDb` for batches and caching.
+pub struct RawSled {
+    db: sled::Db,
+}
+
+impl From<sled::Error> for Error {
+    fn from(err: sled::Error) -> Self {
+        Error::Backend(Box::new(err))
+    }
+}
+
+impl RawSled {
+    /// Use a sled handle already opened, which can be shared with others by cloning
+    pub fn from_db(db: sled::Db) -> Self {
+        RawSled { db }
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+impl Database for RawSled {
+    fn new(dbpath: &str) -> Self {
+        Self::open(dbpath).expect("new(): sledDB")
+    }
+
+    fn open(dbpath: &str) -> Result<Self> {
+        Ok(Self::from_db(sled::open(dbpath)?))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.db.insert(key, value)?;
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.db.remove(key)?;
+        Ok(())
+    }
+
+    fn write_batch(&mut self, writes: Batch) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in writes {
+            match value {
+                Some(value) => batch.insert(key, value),
+                None => batch.remove(key),
+            }
+        }
+        self.db.apply_batch(batch)?;
+        Ok(())
+    }
+}
+
+impl SharedDatabase for RawSled {
+    fn share(&self) -> Result<Self> {
+        Ok(Self::from_db(self.db.clone()))
+    }
+}
+
+impl IterableDatabase for RawSled {
+    fn keys(&self) -> Result<Keys<'_>> {
+        Ok(hash_keys(self.db.iter().keys().map(|key| Ok(key?))))
+    }
+}
+
+/// LMDB adapter. Each read goes through a read transaction of its own, and `write_batch()`
+/// commits in a single write transaction, so that readers see a batch all or nothing.
+/// Wrap it in `BatchingDb` and `CachedDb` for batches and caching, as in `CachedLmdb`.
+/// An environment is opened only once in a process: hand it over to others by `share()`.
+#[cfg(feature = "lmdb")]
+pub struct Lmdb {
+    env: Arc<Environment>,
+    db: lmdb::Database,
+}
+
+#[cfg(feature = "lmdb")]
+impl From<lmdb::Error> for Error {
+    fn from(err: lmdb::Error) -> Self {
+        Error::Backend(Box::new(err))
+    }
+}
+
+#[cfg(feature = "lmdb")]
+impl Lmdb {
+    /// Open an LMDB environment with a memory map of the size given in bytes,
+    /// instead of `DEFAULT_MAP_SIZE`
+    pub fn open_with(dbpath: &str, map_size: usize) -> Result<Self> {
+        fs::create_dir_all(dbpath)?;
+        let env = Environment::new()
+            .set_map_size(map_size)
+            .open(Path::new(dbpath))?;
+        let db = env.open_db(None)?;
+        Ok(Lmdb {
+            env: Arc::new(env),
+            db,
+        })
+    }
+}
+
+#[cfg(feature = "lmdb")]
+impl Database for Lmdb {
+    fn new(dbpath: &str) -> Self {
+        Self::open(dbpath).expect("new(): lmdb")
+    }
+
+    fn open(dbpath: &str) -> Result<Self> {
+        Self::open_with(dbpath, DEFAULT_MAP_SIZE)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let txn = self.env.begin_ro_txn()?;
+        match txn.get(self.db, &key) {
+            Ok(value) => Ok(Some(value.to_vec())),
+            Err(lmdb::Error::NotFound) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.write_batch(vec![(key.to_vec(), Some(value))])
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.write_batch(vec![(key.to_vec(), None)])
+    }
+
+    fn write_batch(&mut self, writes: Batch) -> Result<()> {
+        let mut txn = self.env.begin_rw_txn()?;
+        for (key, value) in writes {
+            match value {
+                Some(value) => txn.put(self.db, &key, &value, WriteFlags::empty())?,
+                None => match txn.del(self.db, &key, None) {
+                    Ok(()) | Err(lmdb::Error::NotFound) => {}
+                    Err(err) => return Err(err.into()),
+                },
+            }
+        }
+        Ok(txn.commit()?)
+    }
+}
+
+#[cfg(feature = "lmdb")]
+impl SharedDatabase for Lmdb {
+    fn share(&self) -> Result<Self> {
+        Ok(Lmdb {
+            env: Arc::clone(&self.env),
+            db: self.db,
+        })
+    }
+}
+
+#[cfg(feature = "lmdb")]
+impl IterableDatabase for Lmdb {
+    fn keys(&self) -> Result<Keys<'_>> {
+        let read = move |last: Option<&[u8]>| -> Result<Vec<Vec<u8>>> {
+            let txn = self.env.begin_ro_txn()?;
+            let mut cursor = txn.open_ro_cursor(self.db)?;
+            // `iter_start()` panics on an empty database, while a new cursor starts from the first.
+            // `iter_from()` panics past the last key, which is checked for first.
+            let iter = match last {
+                None => cursor.iter(),
+                Some(last) => match cursor.get(Some(last), None, lmdb_sys::MDB_SET_RANGE) {
+                    Err(lmdb::Error::NotFound) => return Ok(Vec::new()),
+                    found => {
+                        found?;
+                        cursor.iter_from(last)
+                    }
+                },
+            };
+            let keys = iter
+                .map(|(key, _)| key)
+                .filter(|key| Some(*key) != last)
+                .take(KEYS_PAGE_LEN)
+                .map(|key| key.to_vec())
+                .collect();
+            Ok(keys)
+        };
+        Ok(hash_keys(Pages::new(read)))
+    }
+}
+
+/// SQLite adapter, keeping the nodes in a single file, in a table of
+/// `nodes(hash BLOB PRIMARY KEY, bytes BLOB)`. A batch is a transaction of its own:
+/// `init_batch()` begins it and `finish_batch()` commits it, so there is no need for
+/// `BatchingDb` here. Wrap it in `CachedDb` for caching, as in `CachedSqlite`.
+/// The file is opened in WAL mode, so that readers see what was committed before they
+/// started while a batch is in progress. `share()` opens another connection to the file,
+/// thus an in-memory database (`:memory:`) cannot be shared.
+#[cfg(feature = "rusqlite")]
+pub struct Sqlite {
+    path: String,
+    conn: Mutex<Connection>,
+}
+
+#[cfg(feature = "rusqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Self {
+        Error::Backend(Box::new(err))
+    }
+}
+
+#[cfg(feature = "rusqlite")]
+impl Sqlite {
+    /// Use a SQLite connection already opened to the file at the path given,
+    /// creating the table of nodes if it is not there yet
+    pub fn from_conn(path: &str, conn: Connection) -> Result<Self> {
+        conn.query_row("PRAGMA journal_mode = WAL", NO_PARAMS, |_| Ok(()))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS nodes (hash BLOB PRIMARY KEY, bytes BLOB NOT NULL)",
+            NO_PARAMS,
+        )?;
+        Ok(Sqlite {
+            path: path.to_string(),
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn put_with(conn: &Connection, key: &[u8], value: &[u8]) -> Result<()> {
+        conn.prepare_cached("INSERT OR REPLACE INTO nodes (hash, bytes) VALUES (?1, ?2)")?
+            .execute(params![key, value])?;
+        Ok(())
+    }
+
+    fn delete_with(conn: &Connection, key: &[u8]) -> Result<()> {
+        conn.prepare_cached("DELETE FROM nodes WHERE hash = ?1")?
+            .execute(params![key])?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rusqlite")]
+impl Database for Sqlite {
+    fn new(dbpath: &str) -> Self {
+        Self::open(dbpath).expect("new(): sqlite")
+    }
+
+    fn open(dbpath: &str) -> Result<Self> {
+        if let Some(dir) = Path::new(dbpath).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Self::from_conn(dbpath, Connection::open(dbpath)?)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let conn = self.conn.lock().expect("get(): sqlite");
+        let value = conn
+            .prepare_cached("SELECT bytes FROM nodes WHERE hash = ?1")?
+            .query_row(params![key], |row| row.get(0))
+            .optional()?;
+        Ok(value)
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        Self::put_with(self.conn.get_mut().expect("put(): sqlite"), key, &value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        Self::delete_with(self.conn.get_mut().expect("delete(): sqlite"), key)
+    }
 
     fn init_batch(&mut self) -> Result<()> {
+        let conn = self.conn.get_mut().expect("init_batch(): sqlite");
+        // a transaction still open is of a batch abandoned before `finish_batch()`
+        if !conn.is_autocommit() {
+            conn.execute_batch("ROLLBACK")?;
+        }
+        conn.execute_batch("BEGIN IMMEDIATE")?;
         Ok(())
     }
 
     fn finish_batch(&mut self) -> Result<()> {
+        let conn = self.conn.get_mut().expect("finish_batch(): sqlite");
+        if !conn.is_autocommit() {
+            conn.execute_batch("COMMIT")?;
+        }
         Ok(())
     }
+
+    fn write_batch(&mut self, writes: Batch) -> Result<()> {
+        // a savepoint is a transaction of its own, or nests in the batch in progress
+        let conn = self.conn.get_mut().expect("write_batch(): sqlite");
+        let savepoint = conn.savepoint()?;
+        for (key, value) in writes {
+            match value {
+                Some(value) => Self::put_with(&savepoint, &key, &value)?,
+                None => Self::delete_with(&savepoint, &key)?,
+            }
+        }
+        Ok(savepoint.commit()?)
+    }
 }
 
-pub struct RocksDB {
-    db: Arc<Mutex<DB>>,
-    batch: WriteBatch,
-    cache: MemCache,
-    batch_on: bool,
+#[cfg(feature = "rusqlite")]
+impl SharedDatabase for Sqlite {
+    fn share(&self) -> Result<Self> {
+        if self.path.is_empty() || self.path == ":memory:" {
+            return Err(Error::Backend(
+                "share(): an in-memory sqlite cannot be shared".into(),
+            ));
+        }
+        Self::open(&self.path)
+    }
 }
 
-impl From<rocksdb::Error> for Errors {
-    fn from(err: rocksdb::Error) -> Self {
-        Errors::new(&err.to_string())
+#[cfg(feature = "rusqlite")]
+impl IterableDatabase for Sqlite {
+    fn keys(&self) -> Result<Keys<'_>> {
+        let read = move |last: Option<&[u8]>| -> Result<Vec<Vec<u8>>> {
+            let conn = self.conn.lock().expect("keys(): sqlite");
+            let mut stmt = conn
+                .prepare_cached("SELECT hash FROM nodes WHERE hash > ?1 ORDER BY hash LIMIT ?2")?;
+            let keys = stmt
+                .query_map(params![last.unwrap_or(&[]), KEYS_PAGE_LEN as i64], |row| {
+                    row.get(0)
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(keys)
+        };
+        Ok(hash_keys(Pages::new(read)))
     }
 }
 
-impl Database for RocksDB {
-    fn new(dbpath: &str) -> Self {
-        let db = Arc::new(Mutex::new(
-            DB::open_default(Path::new(dbpath)).expect("new(): rocksdb"),
-        ));
-        RocksDB {
-            db,
-            batch: WriteBatch::default(),
-            cache: MemCache::new(),
+/// Record header of `FlatFile`: lengths of the key and of the value, in little endian,
+/// then the checksum of the record given by `record_checksum()`
+const RECORD_HEADER_LEN: usize = 12;
+
+/// Value length marking a record of `FlatFile` as a deletion
+const TOMBSTONE: u32 = u32::MAX;
+
+/// Checksum of a record of `FlatFile`: the first bytes of the BLAKE3 hash
+/// of the lengths in its header, its key and its value
+fn record_checksum(lens: &[u8], key: &[u8], value: &[u8]) -> [u8; 4] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(lens);
+    hasher.update(key);
+    hasher.update(value);
+    let mut checksum = [0u8; 4];
+    checksum.copy_from_slice(&hasher.finalize().as_bytes()[..4]);
+    checksum
+}
+
+/// Where the value of a record of `FlatFile` is: segment, offset in it, and length
+#[derive(Clone, Copy)]
+struct Location {
+    segment: u64,
+    offset: u64,
+    len: u32,
+}
+
+/// Append-only store of nodes in flat files. Nodes are immutable and content-addressed,
+/// so writing is only appending records of `(hash, bytes)`, or tombstones for deletions,
+/// to the last of a series of segment files, with an index of the records in memory.
+/// The index is rebuilt from the segments on open, truncating a record left half-written
+/// or corrupt, as told by the checksum of each record.
+///
+/// The records of a batch are appended all at once by `finish_batch()`, which syncs them
+/// to the disk, or dropped by the next `init_batch()` if the batch is left unfinished.
+/// Outside a batch, each write is appended as it comes, without syncing.
+/// Thus, there is no need for `BatchingDb` here. Wrap it in `CachedDb` for caching,
+/// as in `CachedFlatFile`. Space is taken back by `compact()`.
+pub struct FlatFile {
+    dir: PathBuf,
+    segment_size: u64,
+    index: HashMap<Vec<u8>, Location>,
+    readers: Mutex<HashMap<u64, File>>,
+    writer: File,
+    active: u64,
+    written: u64,
+    pending: Vec<u8>,
+    batch_on: bool,
+    // the length of the records pending when the batch began,
+    // and what the index pointed at before each write of the batch
+    batch_start: usize,
+    undo: Vec<(Vec<u8>, Option<Location>)>,
+}
+
+impl FlatFile {
+    /// Open the segments in the directory given, going on to a new segment
+    /// beyond the size given in bytes, instead of `DEFAULT_SEGMENT_SIZE`
+    pub fn open_with(dbpath: &str, segment_size: u64) -> Result<Self> {
+        let dir = PathBuf::from(dbpath);
+        fs::create_dir_all(&dir)?;
+        let mut segments: Vec<u64> = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("seg") {
+                if let Some(id) = path.file_stem().and_then(|id| id.to_str()?.parse().ok()) {
+                    segments.push(id);
+                }
+            }
+        }
+        segments.sort_unstable();
+        let mut index = HashMap::new();
+        let mut readers = HashMap::new();
+        for (i, id) in segments.iter().enumerate() {
+            let file = File::open(Self::segment_path(&dir, *id))?;
+            let len = Self::scan(&file, *id, &mut index)?;
+            if len < file.metadata()?.len() {
+                if i + 1 < segments.len() {
+                    return Err(
+                        io::Error::new(io::ErrorKind::InvalidData, "truncated segment").into(),
+                    );
+                }
+                OpenOptions::new()
+                    .write(true)
+                    .open(Self::segment_path(&dir, *id))?
+                    .set_len(len)?;
+            }
+            readers.insert(*id, file);
+        }
+        let active = segments.last().copied().unwrap_or(0);
+        let (writer, reader) = Self::open_segment(&dir, active)?;
+        let written = writer.metadata()?.len();
+        readers.insert(active, reader);
+        Ok(FlatFile {
+            dir,
+            segment_size,
+            index,
+            readers: Mutex::new(readers),
+            writer,
+            active,
+            written,
+            pending: Vec::new(),
             batch_on: false,
+            batch_start: 0,
+            undo: Vec::new(),
+        })
+    }
+
+    fn segment_path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(format!("{:010}.seg", id))
+    }
+
+    /// Open a segment for appending and for reading, creating it if it is not there yet
+    fn open_segment(dir: &Path, id: u64) -> Result<(File, File)> {
+        let path = Self::segment_path(dir, id);
+        let writer = OpenOptions::new().create(true).append(true).open(&path)?;
+        Self::sync_dir(dir)?;
+        Ok((writer, File::open(&path)?))
+    }
+
+    /// Sync the directory, so that the segments created or removed in it
+    /// are still there, or gone, after a crash
+    fn sync_dir(dir: &Path) -> Result<()> {
+        // a directory cannot be opened as a file but on unix
+        if cfg!(unix) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Read the records of a segment into the index, returning the length of the segment
+    /// up to the last sound record. A record is not, and neither is any after it,
+    /// if its lengths run past the end of the segment or its checksum does not match,
+    /// as when it is left half-written.
+    fn scan(file: &File, segment: u64, index: &mut HashMap<Vec<u8>, Location>) -> Result<u64> {
+        let end = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut offset = 0;
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        loop {
+            match reader.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(offset),
+                Err(err) => return Err(err.into()),
+            }
+            let (lens, checksum) = header.split_at(8);
+            let (key_len, len) = lens.split_at(4);
+            let key_len = u32::from_le_bytes(key_len.try_into().expect("scan(): header"));
+            let len = u32::from_le_bytes(len.try_into().expect("scan(): header"));
+            let start = offset + RECORD_HEADER_LEN as u64 + key_len as u64;
+            let value_len = if len == TOMBSTONE { 0 } else { len as u64 };
+            if start + value_len > end {
+                return Ok(offset);
+            }
+            let mut key = vec![0u8; key_len as usize];
+            reader.read_exact(&mut key)?;
+            let mut value = vec![0u8; value_len as usize];
+            reader.read_exact(&mut value)?;
+            if record_checksum(lens, &key, &value) != checksum {
+                return Ok(offset);
+            }
+            match len {
+                TOMBSTONE => {
+                    index.remove(&key);
+                }
+                _ => {
+                    index.insert(
+                        key,
+                        Location {
+                            segment,
+                            offset: start,
+                            len,
+                        },
+                    );
+                }
+            }
+            offset = start + value_len;
         }
     }
 
-    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        if self.cache.contains(key) {
-            return self.cache.get(key);
+    /// Append a record to those pending, returning where its value is
+    fn append(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<Location> {
+        let (key_len, len) = Self::record_lens(key, value)?;
+        let lens = [key_len.to_le_bytes(), len.to_le_bytes()].concat();
+        let checksum = record_checksum(&lens, key, value.unwrap_or_default());
+        self.pending.extend_from_slice(&lens);
+        self.pending.extend_from_slice(&checksum);
+        self.pending.extend_from_slice(key);
+        let offset = self.written + self.pending.len() as u64;
+        if let Some(value) = value {
+            self.pending.extend_from_slice(value);
         }
-        let db = self.db.lock().expect("get(): rocksdb");
-        match db.get(key)? {
-            Some(value) => {
-                self.cache.put(key, value.to_owned())?;
-                Ok(Some(value))
+        Ok(Location {
+            segment: self.active,
+            offset,
+            len,
+        })
+    }
+
+    /// Append a record, pointing the index at it.
+    /// Within a batch, what the index pointed at before is kept to undo it.
+    fn append_indexed(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let loc = self.append(key, value)?;
+        let prev = match value {
+            Some(_) => self.index.insert(key.to_vec(), loc),
+            None => self.index.remove(key),
+        };
+        if self.batch_on {
+            self.undo.push((key.to_vec(), prev));
+        }
+        Ok(())
+    }
+
+    /// Drop the records of the batch, pointing the index back at what it was
+    /// before the batch, the last write undone first
+    fn rollback(&mut self) {
+        self.pending.truncate(self.batch_start);
+        while let Some((key, prev)) = self.undo.pop() {
+            match prev {
+                Some(loc) => self.index.insert(key, loc),
+                None => self.index.remove(&key),
+            };
+        }
+    }
+
+    /// Lengths of the key and of the value of a record, as written in its header.
+    /// Both must be below 4 GiB, and the length of a value is other than `TOMBSTONE`.
+    fn record_lens(key: &[u8], value: Option<&[u8]>) -> Result<(u32, u32)> {
+        let too_long = || {
+            Error::from(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "record too long",
+            ))
+        };
+        let key_len = u32::try_from(key.len()).map_err(|_| too_long())?;
+        let len = match value {
+            Some(value) => u32::try_from(value.len())
+                .ok()
+                .filter(|len| *len != TOMBSTONE)
+                .ok_or_else(too_long)?,
+            None => TOMBSTONE,
+        };
+        Ok((key_len, len))
+    }
+
+    /// Write the pending records to the active segment,
+    /// going on to a new one if it has grown beyond the segment size
+    fn flush(&mut self) -> Result<()> {
+        if !self.pending.is_empty() {
+            self.writer.write_all(&self.pending)?;
+            self.written += self.pending.len() as u64;
+            self.pending.clear();
+        }
+        if self.written >= self.segment_size {
+            self.roll()?;
+        }
+        Ok(())
+    }
+
+    /// Sync the active segment and go on to a new one. No record may be pending.
+    fn roll(&mut self) -> Result<()> {
+        self.writer.sync_data()?;
+        let (writer, reader) = Self::open_segment(&self.dir, self.active + 1)?;
+        self.active += 1;
+        self.writer = writer;
+        self.written = 0;
+        self.readers
+            .get_mut()
+            .expect("roll(): flatfile")
+            .insert(self.active, reader);
+        Ok(())
+    }
+
+    fn read(&self, loc: &Location) -> Result<Vec<u8>> {
+        if loc.segment == self.active && loc.offset >= self.written {
+            let start = (loc.offset - self.written) as usize;
+            return Ok(self.pending[start..start + loc.len as usize].to_vec());
+        }
+        let mut readers = self.readers.lock().expect("read(): flatfile");
+        let file = readers.get_mut(&loc.segment).ok_or(Error::CorruptNode)?;
+        let mut value = vec![0u8; loc.len as usize];
+        file.seek(SeekFrom::Start(loc.offset))?;
+        file.read_exact(&mut value)?;
+        Ok(value)
+    }
+
+    /// Compact the segments, keeping only the nodes and leaves under the roots given.
+    /// They are copied to new segments, then the old segments are removed, the oldest first.
+    /// The index of the new segments is built on the side, and replaces the one in use
+    /// only once they are all written, so that the old segments are read on error.
+    /// Records under keys other than hashes are kept as they are.
+    /// As with `Monotree::prune()`, any root not given here is no longer available after this.
+    /// Returns the number of nodes dropped.
+    pub fn compact(&mut self, keep_roots: &[Hash]) -> Result<usize> {
+        self.finish_batch()?;
+        let live = mark(self, keep_roots)?;
+        let mut old: Vec<u64> = self
+            .readers
+            .get_mut()
+            .expect("compact(): flatfile")
+            .keys()
+            .copied()
+            .collect();
+        old.sort_unstable();
+        self.roll()?;
+        let entries: Vec<(Vec<u8>, Location)> = self
+            .index
+            .iter()
+            .filter(|(key, _)| key.len() != HASH_LEN || live.contains(key.as_slice()))
+            .map(|(key, loc)| (key.to_owned(), *loc))
+            .collect();
+        let dropped = self.index.len() - entries.len();
+        let mut index = HashMap::with_capacity(entries.len());
+        for (key, loc) in entries {
+            let value = self.read(&loc)?;
+            let loc = self.append(&key, Some(&value))?;
+            index.insert(key, loc);
+            if self.pending.len() as u64 >= self.segment_size {
+                self.flush()?;
             }
+        }
+        self.flush()?;
+        self.writer.sync_data()?;
+        self.index = index;
+        // the oldest first, so that a crash on the way never leaves a record
+        // without the later ones deleting or replacing it
+        let readers = self.readers.get_mut().expect("compact(): flatfile");
+        for id in old {
+            readers.remove(&id);
+            fs::remove_file(Self::segment_path(&self.dir, id))?;
+        }
+        Self::sync_dir(&self.dir)?;
+        Ok(dropped)
+    }
+}
+
+impl Database for FlatFile {
+    fn new(dbpath: &str) -> Self {
+        Self::open(dbpath).expect("new(): flatfile")
+    }
+
+    fn open(dbpath: &str) -> Result<Self> {
+        Self::open_with(dbpath, DEFAULT_SEGMENT_SIZE)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.index.get(key) {
+            Some(loc) => Ok(Some(self.read(loc)?)),
             None => Ok(None),
         }
     }
 
     fn put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
-        self.cache.put(key, value.to_owned())?;
-        if self.batch_on {
-            Ok(self.batch.put(key, value)?)
-        } else {
-            let db = self.db.lock().expect("put(): rocksdb");
-            Ok(db.put(key, value)?)
+        self.append_indexed(key, Some(&value))?;
+        match self.batch_on {
+            true => Ok(()),
+            false => self.flush(),
         }
     }
 
     fn delete(&mut self, key: &[u8]) -> Result<()> {
-        self.cache.delete(key)?;
-        if self.batch_on {
-            Ok(self.batch.delete(key)?)
-        } else {
-            let db = self.db.lock().expect("remove(): rocksdb");
-            Ok(db.delete(key)?)
+        if !self.index.contains_key(key) {
+            return Ok(());
+        }
+        self.append_indexed(key, None)?;
+        match self.batch_on {
+            true => Ok(()),
+            false => self.flush(),
         }
     }
 
     fn init_batch(&mut self) -> Result<()> {
-        self.batch = WriteBatch::default();
-        self.cache.clear();
+        // the next batch begins afresh, without the writes left behind
+        if self.batch_on {
+            self.rollback();
+        }
         self.batch_on = true;
+        self.batch_start = self.pending.len();
         Ok(())
     }
 
     fn finish_batch(&mut self) -> Result<()> {
         self.batch_on = false;
-        if !self.batch.is_empty() {
-            let batch = std::mem::take(&mut self.batch);
-            let db = self.db.lock().expect("write_batch(): rocksdb");
-            db.write(batch)?;
-        }
-        Ok(())
+        self.undo.clear();
+        self.flush()?;
+        Ok(self.writer.sync_data()?)
     }
-}
 
-pub struct Sled {
-    db: sled::Db,
-    batch: sled::Batch,
-    cache: MemCache,
-    batch_on: bool,
+    fn write_batch(&mut self, writes: Batch) -> Result<()> {
+        // checked up front, so that none of the batch is appended if any cannot be
+        for (key, value) in writes.iter() {
+            Self::record_lens(key, value.as_deref())?;
+        }
+        for (key, value) in writes {
+            match value {
+                Some(value) => self.append_indexed(&key, Some(&value))?,
+                None if self.index.contains_key(&key) => self.append_indexed(&key, None)?,
+                None => {}
+            }
+        }
+        match self.batch_on {
+            true => Ok(()),
+            false => self.finish_batch(),
+        }
+    }
 }
 
-impl From<sled::Error> for Errors {
-    fn from(err: sled::Error) -> Self {
-        Errors::new(&err.to_string())
+impl IterableDatabase for FlatFile {
+    fn keys(&self) -> Result<Keys<'_>> {
+        Ok(hash_keys(self.index.keys().map(Ok)))
     }
 }
 
+/// RocksDB with batches and a cache of nodes
+pub type RocksDB = CachedDb<BatchingDb<RawRocksDB>>;
+
+/// Sled with batches and a cache of nodes
+pub type Sled = CachedDb<BatchingDb<RawSled>>;
+
 impl Sled {
     pub fn flush(&self) -> Result<()> {
-        self.db.flush()?;
-        Ok(())
+        self.db.db.flush()
     }
 }
 
-impl Database for Sled {
-    fn new(dbpath: &str) -> Self {
-        let db = sled::open(dbpath).expect("new(): sledDB");
-        Sled {
+/// Append-only flat files with a cache of nodes, batching on their own
+pub type CachedFlatFile = CachedDb<FlatFile>;
+
+/// LMDB with batches and a cache of nodes
+#[cfg(feature = "lmdb")]
+pub type CachedLmdb = CachedDb<BatchingDb<Lmdb>>;
+
+/// SQLite with a cache of nodes, batching in its own transactions
+#[cfg(feature = "rusqlite")]
+pub type CachedSqlite = CachedDb<Sqlite>;
+
+/// Caching layer over any `Database`, keeping what is read and written
+/// in a byte-bounded LRU cache.
+pub struct CachedDb<D: Database> {
+    db: D,
+    cache: Mutex<LruCache>,
+}
+
+impl<D: Database> CachedDb<D> {
+    /// Cache on top of a database already opened
+    pub fn from_db(db: D) -> Self {
+        CachedDb {
             db,
-            batch: sled::Batch::default(),
-            cache: MemCache::new(),
-            batch_on: false,
+            cache: Mutex::new(LruCache::new(DEFAULT_CACHE_SIZE)),
         }
     }
 
-    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        if self.cache.contains(key) {
-            return self.cache.get(key);
+    /// Bound the cache to the size given in bytes, instead of `DEFAULT_CACHE_SIZE`
+    pub fn with_cache_size(self, size: usize) -> Self {
+        CachedDb {
+            cache: Mutex::new(LruCache::new(size)),
+            ..self
+        }
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().expect("cache_stats(): cache").stats
+    }
+
+    /// The database under the cache, such as to compact it.
+    /// What is written through it bypasses the cache.
+    pub fn db_mut(&mut self) -> &mut D {
+        &mut self.db
+    }
+}
+
+impl<D: Database> Database for CachedDb<D> {
+    fn new(dbpath: &str) -> Self {
+        Self::from_db(D::new(dbpath))
+    }
+
+    fn open(dbpath: &str) -> Result<Self> {
+        Ok(Self::from_db(D::open(dbpath)?))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.cache.lock().expect("get(): cache").get(key) {
+            return Ok(value);
         }
         match self.db.get(key)? {
             Some(value) => {
-                self.cache.put(key, value.to_vec())?;
-                Ok(Some(value.to_vec()))
+                let mut cache = self.cache.lock().expect("get(): cache");
+                cache.insert(key, value.to_owned());
+                Ok(Some(value))
             }
             None => Ok(None),
         }
     }
 
     fn put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
-        self.cache.put(key, value.to_owned())?;
+        let cache = self.cache.get_mut().expect("put(): cache");
+        cache.put(key, value.to_owned());
+        self.db.put(key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.cache.get_mut().expect("delete(): cache").delete(key);
+        self.db.delete(key)
+    }
+
+    fn init_batch(&mut self) -> Result<()> {
+        self.cache.get_mut().expect("init_batch(): cache").begin();
+        self.db.init_batch()
+    }
+
+    fn finish_batch(&mut self) -> Result<()> {
+        self.db.finish_batch()?;
+        self.cache
+            .get_mut()
+            .expect("finish_batch(): cache")
+            .commit();
+        Ok(())
+    }
+
+    fn write_batch(&mut self, batch: Batch) -> Result<()> {
+        let cache = self.cache.get_mut().expect("write_batch(): cache");
+        for (key, value) in batch.iter() {
+            match value {
+                Some(value) => cache.put(key, value.to_owned()),
+                None => cache.delete(key),
+            }
+        }
+        self.db.write_batch(batch)
+    }
+}
+
+impl<D: SharedDatabase> SharedDatabase for CachedDb<D> {
+    fn share(&self) -> Result<Self> {
+        let size = self.cache.lock().expect("share(): cache").size;
+        Ok(Self::from_db(self.db.share()?).with_cache_size(size))
+    }
+}
+
+impl<D: IterableDatabase> IterableDatabase for CachedDb<D> {
+    fn keys(&self) -> Result<Keys<'_>> {
+        self.db.keys()
+    }
+}
+
+/// Write-batching layer over any `Database`. The writes made between `init_batch()`
+/// and `finish_batch()` are held in memory, where they are read from until the batch is
+/// finished, then handed over to `Database::write_batch()` of the database under it.
+pub struct BatchingDb<D: Database> {
+    db: D,
+    batch: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    batch_on: bool,
+}
+
+impl<D: Database> BatchingDb<D> {
+    /// Batch the writes to a database already opened
+    pub fn from_db(db: D) -> Self {
+        BatchingDb {
+            db,
+            batch: HashMap::new(),
+            batch_on: false,
+        }
+    }
+}
+
+impl<D: Database> Database for BatchingDb<D> {
+    fn new(dbpath: &str) -> Self {
+        Self::from_db(D::new(dbpath))
+    }
+
+    fn open(dbpath: &str) -> Result<Self> {
+        Ok(Self::from_db(D::open(dbpath)?))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.batch.get(key) {
+            Some(value) => Ok(value.to_owned()),
+            None => self.db.get(key),
+        }
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
         if self.batch_on {
-            self.batch.insert(key, value);
+            self.batch.insert(key.to_vec(), Some(value));
+            Ok(())
         } else {
-            self.db.insert(key, value)?;
+            self.db.put(key, value)
         }
-        Ok(())
     }
 
     fn delete(&mut self, key: &[u8]) -> Result<()> {
-        self.cache.delete(key)?;
         if self.batch_on {
-            self.batch.remove(key);
+            self.batch.insert(key.to_vec(), None);
+            Ok(())
         } else {
-            self.db.remove(key)?;
+            self.db.delete(key)
         }
-        Ok(())
     }
 
     fn init_batch(&mut self) -> Result<()> {
-        self.batch = sled::Batch::default();
-        self.cache.clear();
+        self.batch.clear();
         self.batch_on = true;
         Ok(())
     }
 
     fn finish_batch(&mut self) -> Result<()> {
         self.batch_on = false;
-        let batch = std::mem::take(&mut self.batch);
-        self.db.apply_batch(batch)?;
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let writes: Batch = self.batch.drain().collect();
+        self.db.write_batch(writes)
+    }
+
+    fn write_batch(&mut self, batch: Batch) -> Result<()> {
+        if self.batch_on {
+            self.batch.extend(batch);
+            Ok(())
+        } else {
+            self.db.write_batch(batch)
+        }
+    }
+}
+
+impl<D: SharedDatabase> SharedDatabase for BatchingDb<D> {
+    fn share(&self) -> Result<Self> {
+        Ok(Self::from_db(self.db.share()?))
+    }
+}
+
+impl<D: IterableDatabase> IterableDatabase for BatchingDb<D> {
+    fn keys(&self) -> Result<Keys<'_>> {
+        self.db.keys()
+    }
+}
+
+/// Reference-counting layer over any `Database`, for incremental pruning.
+/// It keeps a count for each node of how many live parent nodes and retained roots
+/// point at it. Leaves are counted as well, so that a value stored under a leaf
+/// (see `Monotree::insert_value()`) goes away with the last reference to it.
+/// The counts are stored in the same database, next to the nodes,
+/// so that they are batched along with the nodes through `init_batch()`/`finish_batch()`.
+///
+/// Counting is lazy: a node written by the tree has no count until a root above it is
+/// retained. `retain()` commits a root and drops every node written since the last call
+/// that did not end up under the root, such as intermediate nodes of batch updates.
+/// `release()` frees a root, deleting the nodes whose count reaches zero.
+/// Thus, each new root must be retained before producing the next one.
+/// Nodes left uncounted when the process exits can be collected by `Monotree::prune()`.
+pub struct RefCounted<D: Database> {
+    db: D,
+    pending: HashSet<Hash>,
+}
+
+impl<D: Database> RefCounted<D> {
+    /// Count references on top of a database already opened
+    pub fn from_db(db: D) -> Self {
+        RefCounted {
+            db,
+            pending: HashSet::new(),
+        }
+    }
+
+    fn count_key(key: &[u8]) -> Vec<u8> {
+        [key, b"rc"].concat()
+    }
+
+    fn count(&self, key: &[u8]) -> Result<u64> {
+        match self.db.get(&Self::count_key(key))? {
+            Some(bytes) => Ok(bytes_to_int(&bytes)),
+            None => Ok(0),
+        }
+    }
+
+    fn set_count(&mut self, key: &[u8], count: u64) -> Result<()> {
+        match count {
+            0 => self.db.delete(&Self::count_key(key)),
+            _ => self.db.put(&Self::count_key(key), int_to_bytes(count)),
+        }
+    }
+
+    /// Count a reference to a root, and to the nodes under it which had none before.
+    /// The nodes are walked with a stack of their own as `mark()` does,
+    /// so that the call stack does not grow with how deep the tree goes.
+    fn incr(&mut self, root: &Hash) -> Result<()> {
+        let mut stack: Vec<(Hash, Vec<bool>)> = vec![(*root, Vec::new())];
+        while let Some((key, path)) = stack.pop() {
+            let count = self.count(&key)?;
+            self.set_count(&key, count + 1)?;
+            if count == 0 && !is_complete_key(&path) {
+                let bytes = self.db.get(&key)?.ok_or(Error::MissingNode(key))?;
+                stack.extend(children(&bytes, &path)?);
+            }
+        }
         Ok(())
     }
+
+    /// Drop a reference to a root, and to the nodes under it once it has none left,
+    /// deleting those whose count reaches zero. The nodes are walked as in `incr()`.
+    fn decr(&mut self, root: &Hash) -> Result<()> {
+        let mut stack: Vec<(Hash, Vec<bool>)> = vec![(*root, Vec::new())];
+        while let Some((key, path)) = stack.pop() {
+            match self.count(&key)? {
+                0 => return Err(Error::NotRetained(key)),
+                1 if !is_complete_key(&path) => {
+                    let bytes = self.db.get(&key)?.ok_or(Error::MissingNode(key))?;
+                    self.delete(&key)?;
+                    stack.extend(children(&bytes, &path)?);
+                }
+                1 => self.delete(&key)?,
+                count => self.set_count(&key, count - 1)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Retain a root: count the nodes under it,
+    /// then drop the nodes written so far but not reachable from any retained root.
+    pub fn retain(&mut self, root: &Hash) -> Result<()> {
+        self.db.init_batch()?;
+        self.incr(root)?;
+        let pending = std::mem::take(&mut self.pending);
+        for key in pending.iter() {
+            if self.count(key)? == 0 {
+                self.db.delete(key)?;
+            }
+        }
+        self.db.finish_batch()
+    }
+
+    /// Release a root retained before, deleting the nodes no longer referenced
+    pub fn release(&mut self, root: &Hash) -> Result<()> {
+        if self.count(root)? == 0 {
+            return Err(Error::NotRetained(*root));
+        }
+        self.db.init_batch()?;
+        self.decr(root)?;
+        self.db.finish_batch()
+    }
+}
+
+impl<D: Database> Database for RefCounted<D> {
+    fn new(dbpath: &str) -> Self {
+        Self::from_db(D::new(dbpath))
+    }
+
+    fn open(dbpath: &str) -> Result<Self> {
+        Ok(Self::from_db(D::open(dbpath)?))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.get(key)
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        // Those counted already are kept by `retain()`, which reads the counts anyway
+        if key.len() == HASH_LEN {
+            self.pending.insert(slice_to_hash(key));
+        }
+        self.db.put(key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.pending.remove(key);
+        self.set_count(key, 0)?;
+        self.db.delete(key)
+    }
+
+    fn init_batch(&mut self) -> Result<()> {
+        self.db.init_batch()
+    }
+
+    fn finish_batch(&mut self) -> Result<()> {
+        self.db.finish_batch()
+    }
+}
+
+impl<D: SharedDatabase> SharedDatabase for RefCounted<D> {
+    fn share(&self) -> Result<Self> {
+        Ok(Self::from_db(self.db.share()?))
+    }
+}
+
+impl<D: IterableDatabase> IterableDatabase for RefCounted<D> {
+    fn keys(&self) -> Result<Keys<'_>> {
+        self.db.keys()
+    }
 }
@@ -0,0 +1,219 @@
+use crate::utils::*;
+use crate::*;
+use std::cmp;
+use std::ops::{Bound, RangeBounds};
+
+impl<D, H> Monotree<D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    /// Iterate over all entries under the given root, yielding pairs of `(key, leaf)`.
+    /// It walks down `Node::Soft` and `Node::Hard` in bit order, the left before the right,
+    /// so that the keys come out in lexicographic order.
+    /// The keys are rebuilt from the bits accumulated on the way down to each leaf.
+    /// The iterator is lazy: it reads a node from the database only when it gets there,
+    /// keeping no more than the nodes on the current path and their siblings.
+    ///
+    /// ```
+    /// use monotree::utils::random_hashes;
+    /// use monotree::{Monotree, Result};
+    ///
+    /// fn example() -> Result<()> {
+    ///     let mut tree = Monotree::default();
+    ///     let mut keys = random_hashes(100);
+    ///     let leaves = random_hashes(100);
+    ///     let root = tree.inserts(None, &keys, &leaves)?;
+    ///
+    ///     let entries = tree.iter(root.as_ref()).collect::<Result<Vec<_>>>()?;
+    ///     keys.sort();
+    ///     assert_eq!(entries.len(), keys.len());
+    ///     assert_eq!(entries[0].0, keys[0]);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn iter(&self, root: Option<&Hash>) -> Iter<'_, D, H> {
+        let stack = match root {
+            None => Vec::new(),
+            Some(root) => vec![(Vec::new(), *root)],
+        };
+        Iter {
+            tree: self,
+            stack,
+            lower: Bound::Unbounded,
+            upper: Bound::Unbounded,
+            prefix: Vec::new(),
+        }
+    }
+
+    /// Iterate over the entries whose keys fall in the given range, in the order of keys.
+    /// Subtrees whose compressed path falls outside the range are skipped without reading.
+    ///
+    /// ```
+    /// use monotree::utils::random_hashes;
+    /// use monotree::{Monotree, Result};
+    ///
+    /// fn example() -> Result<()> {
+    ///     let mut tree = Monotree::default();
+    ///     let mut keys = random_hashes(100);
+    ///     let leaves = random_hashes(100);
+    ///     let root = tree.inserts(None, &keys, &leaves)?;
+    ///
+    ///     keys.sort();
+    ///     let entries = tree
+    ///         .range(root.as_ref(), keys[10]..keys[20])?
+    ///         .collect::<Result<Vec<_>>>()?;
+    ///     assert_eq!(entries.len(), 10);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        root: Option<&Hash>,
+        range: R,
+    ) -> Result<Iter<'_, D, H>> {
+        let bound = |bound: Bound<&K>| -> Result<Bound<Vec<bool>>> {
+            let bits =
+                |key: &K| -> Result<Vec<bool>> { Ok(bytes_to_bits(&encode_key(key.as_ref())?)) };
+            match bound {
+                Bound::Included(key) => Ok(Bound::Included(bits(key)?)),
+                Bound::Excluded(key) => Ok(Bound::Excluded(bits(key)?)),
+                Bound::Unbounded => Ok(Bound::Unbounded),
+            }
+        };
+        let (lower, upper) = (bound(range.start_bound())?, bound(range.end_bound())?);
+        Ok(Iter {
+            lower,
+            upper,
+            ..self.iter(root)
+        })
+    }
+
+    /// Iterate over the entries whose keys start with the given prefix, in the order of keys.
+    /// Only the subtrees along the prefix are read.
+    pub fn prefix(&self, root: Option<&Hash>, prefix: &[u8]) -> Result<Iter<'_, D, H>> {
+        let bits = bytes_to_bits(&encode_key(prefix)?);
+        Ok(Iter {
+            prefix: bits[..bits.len() - 16].to_vec(),
+            ..self.iter(root)
+        })
+    }
+}
+
+impl<'a, D, H> Iter<'a, D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    /// Push the units of a node onto the stack, the right first, so that the left comes out first.
+    /// The units leading to no keys within the bounds are left out.
+    fn expand(&mut self, path: &[bool], hash: &Hash) -> Result<()> {
+        let bytes = self.tree.get_node(hash)?;
+        let (lc, rc) = Node::cells_from_bytes(&bytes, false)?;
+        for unit in [rc, lc].iter().flatten() {
+            let path = join_path(path, &unit.bits)?;
+            if is_within(&path, &self.lower, &self.upper, &self.prefix) {
+                self.stack.push((path, slice_to_hash(unit.hash)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, D, H> Iterator for Iter<'a, D, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    type Item = Result<(Vec<u8>, Hash)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((path, hash)) = self.stack.pop() {
+            if is_complete_key(&path) {
+                match decode_key(&bits_to_bytes(&path)) {
+                    Some(key) => return Some(Ok((key, hash))),
+                    None => {
+                        self.stack.clear();
+                        return Some(Err(Error::CorruptNode));
+                    }
+                }
+            }
+            if let Err(err) = self.expand(&path, &hash) {
+                self.stack.clear();
+                return Some(Err(err));
+            }
+        }
+        None
+    }
+}
+
+/// Tell if any key under the path can be within the bounds and under the prefix.
+/// Let `n` be the length of the common prefix of the path and a bound.
+/// Where both go on after `n` bits, the bit right there decides the order of all keys
+/// under the path against the bound. Where the bound ends at `n` bits, the path is
+/// the bound itself as no encoded key is a prefix of another.
+pub(crate) fn is_within(
+    path: &[bool],
+    lower: &Bound<Vec<bool>>,
+    upper: &Bound<Vec<bool>>,
+    prefix: &[bool],
+) -> bool {
+    let lcp = |bits: &[bool]| path.iter().zip(bits).take_while(|(a, b)| a == b).count();
+    let below = match lower {
+        Bound::Included(bits) => {
+            let n = lcp(bits);
+            n < path.len() && n < bits.len() && bits[n]
+        }
+        Bound::Excluded(bits) => {
+            let n = lcp(bits);
+            n == bits.len() || (n < path.len() && bits[n])
+        }
+        Bound::Unbounded => false,
+    };
+    let above = match upper {
+        Bound::Included(bits) => {
+            let n = lcp(bits);
+            n < path.len() && n < bits.len() && path[n]
+        }
+        Bound::Excluded(bits) => {
+            let n = lcp(bits);
+            n == bits.len() || (n < path.len() && path[n])
+        }
+        Bound::Unbounded => false,
+    };
+    !below && !above && lcp(prefix) == min!(path.len(), prefix.len())
+}
+
+/// Tell if all keys under the path are within the bounds.
+/// As in `is_within()`, the bit where the path parts from a bound decides the order,
+/// but here the path must part from the bound before it ends, or be the bound if included.
+pub(crate) fn is_covered(
+    path: &[bool],
+    lower: &Bound<Vec<bool>>,
+    upper: &Bound<Vec<bool>>,
+) -> bool {
+    let lcp = |bits: &[bool]| path.iter().zip(bits).take_while(|(a, b)| a == b).count();
+    let above_lower = match lower {
+        Bound::Included(bits) => {
+            let n = lcp(bits);
+            n == bits.len() || (n < path.len() && path[n])
+        }
+        Bound::Excluded(bits) => {
+            let n = lcp(bits);
+            n < path.len() && n < bits.len() && path[n]
+        }
+        Bound::Unbounded => true,
+    };
+    let below_upper = match upper {
+        Bound::Included(bits) => {
+            let n = lcp(bits);
+            n == bits.len() || (n < path.len() && !path[n])
+        }
+        Bound::Excluded(bits) => {
+            let n = lcp(bits);
+            n < path.len() && n < bits.len() && !path[n]
+        }
+        Bound::Unbounded => true,
+    };
+    above_lower && below_upper
+}
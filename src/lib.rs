@@ -7,6 +7,7 @@
 //! - Very simple, concise and __easy to read__, but __fast__ and robust.  
 //! - __Fully featured__ Sparse Merkle Tree (SMT) as a storage
 //! - <ins>This includes: __non-inclusion proof__ , as well as __inclusion proof__, and its verification.</ins>
+//! - Keys of __arbitrary length__, kept prefix-free by a terminator (See `utils::encode_key()`).
 //! - Again, _NOT verbose_ at all.  
 //!
 //! This library mostly relies on the _Rust standard library only_ except for `database APIs` and `hashers`.  
@@ -16,23 +17,62 @@
 //! - [`HashMap`](https://lib.rs/crates/hashbrown)
 //! - [`RocksDB`](https://lib.rs/crates/rocksdb)
 //! - [`Sled`](https://lib.rs/crates/sled)
+//! - `FlatFile`, append-only segment files native to this crate
+//! - [`LMDB`](https://lib.rs/crates/lmdb), with the `lmdb` feature
+//! - [`SQLite`](https://lib.rs/crates/rusqlite), with the `rusqlite` feature
 //!
 //! _Hashers include_:
 //! - [`Blake3`](https://lib.rs/crates/blake3)
 //! - [`Blake2s`](https://lib.rs/crates/blake2-rfc) and [`Blake2b`](https://lib.rs/crates/blake2-rfc)
 //! - [`SHA-2`](https://lib.rs/crates/sha2)
 //! - [`SHA-3 (Keccak)`](https://lib.rs/crates/sha3)
-use std::error::Error;
+use std::error;
 use std::fmt;
-use std::ops::Range;
+use std::io;
+use std::ops::{Bound, Range};
+use std::sync::Mutex;
 
 pub const HASH_LEN: usize = 32;
 // pub const UNIT_BIT: usize = 4;
 // pub const NL: usize = 1 << UNIT_BIT;
 pub type BitsLen = u16;
-pub type Result<T> = std::result::Result<T, Errors>;
+pub type Result<T> = std::result::Result<T, Error>;
 pub type Hash = [u8; HASH_LEN];
-pub type Proof = Vec<(bool, Vec<u8>)>;
+
+/// Merkle proof: the cuts of the nodes on the path to a leaf, from the root down.
+/// Each cut is the node bytes with the hash of the unit on the path left out,
+/// and is flagged `true` if the unit is the right one of a `Node::Hard`.
+/// See `Proof::encode()` for its wire format.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Proof(pub Vec<(bool, Vec<u8>)>);
+pub mod proof;
+
+/// Proof of absence of a key: `path` is the Merkle path down to `node`,
+/// the node where the key's path diverges from every entry in the tree.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NonInclusionProof {
+    pub path: Proof,
+    #[cfg_attr(feature = "serde", serde(with = "serdes::bytes"))]
+    pub node: Vec<u8>,
+}
+
+/// Proof over a range of keys: the bytes of the nodes on the paths to the bounds, in pre-order.
+/// The hashes of the units on those paths, and of the units within the range, are zeroed out,
+/// as they are rebuilt from the entries when verifying.
+pub type RangeProof = Vec<Vec<u8>>;
+
+/// Proof of inclusion of many keys at once, laid out the same as `RangeProof`
+/// with the nodes on the paths to the keys, whose units on the paths are zeroed out.
+/// The nodes shared by the paths to the keys are put only once.
+pub type MultiProof = Vec<Vec<u8>>;
+
+/// Operation on the entry of a key, in a changeset given to `Monotree::apply()`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op<K> {
+    Put(K, Hash),
+    Delete(K),
+}
 
 #[macro_use]
 pub mod utils;
@@ -42,6 +82,16 @@ pub struct Bits<'a> {
     pub path: &'a [u8],
     pub range: Range<BitsLen>,
 }
+
+/// Owned version of `Bits`, not bound to the bytes it was read from
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "serdes::RawBits"))]
+pub struct OwnedBits {
+    #[cfg_attr(feature = "serde", serde(with = "serdes::bytes"))]
+    pub path: Vec<u8>,
+    pub range: Range<BitsLen>,
+}
 pub mod bits;
 
 pub type Cell<'a> = Option<Unit<'a>>;
@@ -51,6 +101,15 @@ pub enum Node<'a> {
     Soft(Cell<'a>),
     Hard(Cell<'a>, Cell<'a>),
 }
+
+/// Owned version of `Node`
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "serdes::RawNode"))]
+pub enum OwnedNode {
+    Soft(Option<OwnedUnit>),
+    Hard(Option<OwnedUnit>, Option<OwnedUnit>),
+}
 pub mod node;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -59,13 +118,74 @@ pub struct Unit<'a> {
     pub bits: Bits<'a>,
 }
 
+/// Owned version of `Unit`
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OwnedUnit {
+    #[cfg_attr(feature = "serde", serde(with = "serdes::hash"))]
+    pub hash: Hash,
+    pub bits: OwnedBits,
+}
+
+/// Writes of a batch: the values put, or `None` for the keys deleted
+pub type Batch = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
 pub trait Database {
     fn new(dbpath: &str) -> Self;
-    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    /// Fallible version of `new()`, returning an error if the database cannot be opened.
+    /// The databases which never fail to open can leave it to `new()`.
+    fn open(dbpath: &str) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(Self::new(dbpath))
+    }
+    /// Reads go through `&self` so that a tree can be read from many threads at once.
+    /// Databases caching what they read keep the cache behind interior mutability.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
     fn put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()>;
     fn delete(&mut self, key: &[u8]) -> Result<()>;
-    fn init_batch(&mut self) -> Result<()>;
-    fn finish_batch(&mut self) -> Result<()>;
+    /// The writes between `init_batch()` and `finish_batch()` make a batch.
+    /// The databases writing straight through can leave both as they are,
+    /// and get batches from `database::BatchingDb` instead.
+    fn init_batch(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn finish_batch(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Write a batch all at once, atomically if the backend can.
+    /// This is how `database::BatchingDb` hands its batches over to the database under it.
+    fn write_batch(&mut self, batch: Batch) -> Result<()> {
+        for (key, value) in batch {
+            match value {
+                Some(value) => self.put(&key, value)?,
+                None => self.delete(&key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Database whose storage can be reached from more than one handle at a time,
+/// as needed by `SharedMonotree`.
+pub trait SharedDatabase: Database {
+    /// Another handle to the same storage, with a batch and a cache of its own.
+    /// What a handle writes is seen by the others once it is out of the batch.
+    /// Returns an error if no other handle can be had, as for a database in memory.
+    fn share(&self) -> Result<Self>
+    where
+        Self: Sized;
+}
+
+/// Keys listed by `IterableDatabase::keys()`
+pub type Keys<'a> = Box<dyn Iterator<Item = Result<Hash>> + 'a>;
+
+/// Database whose keys can be listed, as needed by `Monotree::prune()`.
+pub trait IterableDatabase: Database {
+    /// The keys of `HASH_LEN` bytes stored, those of the nodes and leaves,
+    /// read lazily so that listing them does not take the whole key set in memory.
+    fn keys(&self) -> Result<Keys<'_>>;
 }
 pub mod database;
 
@@ -83,29 +203,86 @@ pub struct Monotree<D = DefaultDatabase, H = DefaultHasher> {
     db: D,
     pub hasher: H,
 }
+mod bulk;
 pub mod tree;
 
+/// Lazy iterator over the entries under a root, in the order of keys.
+/// It can be bounded by a range and a prefix of keys, given in bits of encoded keys.
+/// See `Monotree::iter()`, `Monotree::range()` and `Monotree::prefix()`.
+pub struct Iter<'a, D = DefaultDatabase, H = DefaultHasher> {
+    tree: &'a Monotree<D, H>,
+    stack: Vec<(Vec<bool>, Hash)>,
+    lower: Bound<Vec<bool>>,
+    upper: Bound<Vec<bool>>,
+    prefix: Vec<bool>,
+}
+pub mod iter;
+
+/// Tree handle for a single writer and many readers across threads.
+/// The readers and the writer hold their own handles to the same database,
+/// so that reading from the roots already committed never waits for the writer.
+/// See `SharedMonotree::writer()`.
+pub struct SharedMonotree<D, H = DefaultHasher> {
+    reader: Monotree<D, H>,
+    writer: Mutex<Monotree<D, H>>,
+}
+pub mod shared;
+
+#[cfg(feature = "serde")]
+pub mod serdes;
+
 #[derive(Debug)]
-pub struct Errors {
-    details: String,
+pub enum Error {
+    /// A node referred to is not found in the database
+    MissingNode(Hash),
+    /// Bytes read as a node are not laid out as one
+    CorruptNode,
+    /// A node released or dereferenced more times than it was retained
+    NotRetained(Hash),
+    /// A key too long to be encoded in `BitsLen` bits, with its length in bytes
+    InvalidKeyLength(usize),
+    /// Malformed proof bytes, with the reason
+    InvalidProof(&'static str),
+    /// Any error from the database backend, such as I/O errors
+    Backend(Box<dyn error::Error + Send + Sync>),
 }
 
-impl Errors {
-    pub fn new(msg: &str) -> Errors {
-        Errors {
-            details: msg.to_string(),
-        }
+/// The former name of `Error`
+#[deprecated(since = "0.2.0", note = "use `Error`")]
+pub type Errors = Error;
+
+impl Error {
+    /// The former constructor of `Errors`, giving an error of the backend with the message
+    #[deprecated(since = "0.2.0", note = "use a variant of `Error`")]
+    pub fn new(msg: &str) -> Error {
+        Error::Backend(msg.into())
     }
 }
 
-impl fmt::Display for Errors {
+impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.details)
+        match self {
+            Error::MissingNode(hash) => write!(f, "missing node: {}", hex::encode(hash)),
+            Error::CorruptNode => write!(f, "corrupt node"),
+            Error::NotRetained(hash) => write!(f, "not retained: {}", hex::encode(hash)),
+            Error::InvalidKeyLength(n) => write!(f, "invalid key length: {}", n),
+            Error::InvalidProof(reason) => write!(f, "invalid proof: {}", reason),
+            Error::Backend(err) => write!(f, "backend: {}", err),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Backend(err) => Some(err.as_ref()),
+            _ => None,
+        }
     }
 }
 
-impl Error for Errors {
-    fn description(&self) -> &str {
-        &self.details
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Backend(Box::new(err))
     }
 }
@@ -24,13 +24,25 @@ impl<'a> Node<'a> {
         }
     }
 
+    /// Parse a unit at the head of the bytes, or at the tail if it is the right one of `Node::Hard`.
+    /// Returns an error if the bytes are too short for the unit,
+    /// or if its bits are out of the form `Bits::to_bytes()` gives.
     fn parse_bytes(bytes: &'a [u8], right: bool) -> Result<(Cell<'a>, usize)> {
         let l = bytes.len();
         let i = if right { 0usize } else { HASH_LEN };
+        if l < HASH_LEN + 4 {
+            return Err(Error::CorruptNode);
+        }
         let g = if right { l - HASH_LEN..l } else { 0..HASH_LEN };
         let start: u16 = bytes_to_int(&bytes[i..i + 2]);
         let end: u16 = bytes_to_int(&bytes[i + 2..i + 4]);
+        if start >= 8 || start >= end {
+            return Err(Error::CorruptNode);
+        }
         let n = nbytes_across(start, end) as usize;
+        if l < HASH_LEN + 4 + n {
+            return Err(Error::CorruptNode);
+        }
         Ok((
             Some(Unit {
                 hash: &bytes[g],
@@ -43,18 +55,27 @@ impl<'a> Node<'a> {
         ))
     }
 
+    /// Parse a node from bytes given by `Node::to_bytes()`.
+    /// Returns `Error::CorruptNode` unless the bytes are exactly laid out as a node.
     pub fn from_bytes(bytes: &'a [u8]) -> Result<Self> {
+        let l = bytes.len();
         match bytes.last() {
             Some(&0x00) => {
-                let (cell, _) = Node::parse_bytes(&bytes[..bytes.len() - 1], false)?;
-                Ok(Node::Soft(cell))
+                let (cell, size) = Node::parse_bytes(&bytes[..l - 1], false)?;
+                match size {
+                    size if size == l - 1 => Ok(Node::Soft(cell)),
+                    _ => Err(Error::CorruptNode),
+                }
             }
             Some(&0x01) => {
-                let (lc, size) = Node::parse_bytes(&bytes, false)?;
-                let (rc, _) = Node::parse_bytes(&bytes[size..bytes.len() - 1], true)?;
-                Ok(Node::Hard(lc, rc))
+                let (lc, size) = Node::parse_bytes(&bytes[..l - 1], false)?;
+                let (rc, rsize) = Node::parse_bytes(&bytes[size..l - 1], true)?;
+                match size + rsize + HASH_LEN {
+                    n if n == l - 1 => Ok(Node::Hard(lc, rc)),
+                    _ => Err(Error::CorruptNode),
+                }
             }
-            _ => unreachable!("Node::from_bytes()"),
+            _ => Err(Error::CorruptNode),
         }
     }
 
@@ -74,7 +95,57 @@ impl<'a> Node<'a> {
                 ]
                 .concat())
             }
-            _ => unreachable!("node.to_bytes()"),
+            _ => Err(Error::CorruptNode),
+        }
+    }
+}
+
+impl<'a> From<&Unit<'a>> for OwnedUnit {
+    fn from(unit: &Unit<'a>) -> Self {
+        OwnedUnit {
+            hash: slice_to_hash(unit.hash),
+            bits: OwnedBits::from(&unit.bits),
+        }
+    }
+}
+
+impl OwnedUnit {
+    pub fn as_unit(&self) -> Unit<'_> {
+        Unit {
+            hash: &self.hash,
+            bits: self.bits.as_bits(),
         }
     }
 }
+
+impl<'a> From<&Node<'a>> for OwnedNode {
+    fn from(node: &Node<'a>) -> Self {
+        match node {
+            Node::Soft(cell) => OwnedNode::Soft(cell.as_ref().map(OwnedUnit::from)),
+            Node::Hard(lc, rc) => OwnedNode::Hard(
+                lc.as_ref().map(OwnedUnit::from),
+                rc.as_ref().map(OwnedUnit::from),
+            ),
+        }
+    }
+}
+
+impl OwnedNode {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(OwnedNode::from(&Node::from_bytes(bytes)?))
+    }
+
+    pub fn as_node(&self) -> Node<'_> {
+        match self {
+            OwnedNode::Soft(cell) => Node::Soft(cell.as_ref().map(OwnedUnit::as_unit)),
+            OwnedNode::Hard(lc, rc) => Node::Hard(
+                lc.as_ref().map(OwnedUnit::as_unit),
+                rc.as_ref().map(OwnedUnit::as_unit),
+            ),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        self.as_node().to_bytes()
+    }
+}
@@ -0,0 +1,152 @@
+use crate::utils::*;
+use crate::*;
+use std::ops::{Deref, DerefMut};
+
+/// The version of the wire format of `Proof`
+pub const PROOF_VERSION: u8 = 0x01;
+
+impl Proof {
+    /// Encode a proof into bytes in a versioned, length-prefixed format:
+    /// - `[version: u8][number of cuts: u16]`, followed by each cut of
+    /// - `[right: u8][length of cut: u16][cut]`
+    ///
+    /// All integers are in big-endian.
+    ///
+    /// ```
+    /// use monotree::utils::random_hashes;
+    /// use monotree::{Monotree, Proof, Result};
+    ///
+    /// fn example() -> Result<()> {
+    ///     let mut tree = Monotree::default();
+    ///     let keys = random_hashes(100);
+    ///     let leaves = random_hashes(100);
+    ///     let root = tree.inserts(None, &keys, &leaves)?;
+    ///     let proof = tree.get_merkle_proof(root.as_ref(), &keys[0])?.unwrap();
+    ///
+    ///     let bytes = proof.encode()?;
+    ///     assert_eq!(Proof::decode(&bytes)?, proof);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        if self.len() > u16::MAX as usize {
+            return Err(Error::InvalidProof("too many cuts"));
+        }
+        let mut bytes = vec![PROOF_VERSION];
+        bytes.extend_from_slice(&(self.len() as u16).to_be_bytes());
+        for (right, cut) in self.iter() {
+            if cut.len() > u16::MAX as usize {
+                return Err(Error::InvalidProof("cut too long"));
+            }
+            bytes.push(*right as u8);
+            bytes.extend_from_slice(&(cut.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(cut);
+        }
+        Ok(bytes)
+    }
+
+    /// Decode a proof from bytes made by `Proof::encode()`.
+    /// Returns an error on malformed input: an unknown version,
+    /// a truncated or trailing byte, or a cut that is not a part of a node.
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader(bytes);
+        if reader.take(1)? != [PROOF_VERSION] {
+            return Err(Error::InvalidProof("unknown version"));
+        }
+        let n: usize = bytes_to_int(reader.take(2)?);
+        let mut proof = Proof::default();
+        for _ in 0..n {
+            let right = match reader.take(1)? {
+                [0x00] => false,
+                [0x01] => true,
+                _ => return Err(Error::InvalidProof("unknown side")),
+            };
+            let l: usize = bytes_to_int(reader.take(2)?);
+            let cut = reader.take(l)?;
+            if !is_valid_cut(right, cut) {
+                return Err(Error::InvalidProof("malformed cut"));
+            }
+            proof.push((right, cut.to_vec()));
+        }
+        if !reader.0.is_empty() {
+            return Err(Error::InvalidProof("trailing bytes"));
+        }
+        Ok(proof)
+    }
+}
+
+impl Deref for Proof {
+    type Target = Vec<(bool, Vec<u8>)>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Proof {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl From<Vec<(bool, Vec<u8>)>> for Proof {
+    fn from(cuts: Vec<(bool, Vec<u8>)>) -> Self {
+        Proof(cuts)
+    }
+}
+
+/// Cursor over bytes being decoded
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(Error::InvalidProof("truncated"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+}
+
+/// Tell if the cut is laid out as a part of a node, so that it can be folded safely.
+/// - `Node::Soft`: `[bits][0x00]`
+/// - left of `Node::Hard`: `[left bits][right bits][right hash][0x01]`
+/// - right of `Node::Hard`: `[left hash][left bits][right bits][0x01]`
+pub(crate) fn is_valid_cut(right: bool, cut: &[u8]) -> bool {
+    let l = cut.len();
+    match (right, cut.last()) {
+        (false, Some(&0x00)) => len_bits_bytes(cut) == Some(l - 1),
+        (false, Some(&0x01)) => {
+            len_bits_bytes(cut)
+                .and_then(|a| Some(a + len_bits_bytes(&cut[a..])?))
+                .map(|n| n + HASH_LEN + 1)
+                == Some(l)
+        }
+        (true, Some(&0x01)) if l > HASH_LEN => {
+            len_bits_bytes(&cut[HASH_LEN..])
+                .and_then(|a| Some(a + len_bits_bytes(&cut[HASH_LEN + a..])?))
+                .map(|n| HASH_LEN + n + 1)
+                == Some(l)
+        }
+        _ => false,
+    }
+}
+
+/// The number of bytes taken by the `Bits` at the head of the bytes, as in `Bits::to_bytes()`
+fn len_bits_bytes(bytes: &[u8]) -> Option<usize> {
+    let u = std::mem::size_of::<BitsLen>();
+    if bytes.len() < 2 * u {
+        return None;
+    }
+    let start: BitsLen = bytes_to_int(&bytes[..u]);
+    let end: BitsLen = bytes_to_int(&bytes[u..2 * u]);
+    if start >= 8 || start >= end {
+        return None;
+    }
+    let n = 2 * u + nbytes_across(start, end) as usize;
+    if n > bytes.len() {
+        return None;
+    }
+    Some(n)
+}
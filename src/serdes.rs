@@ -0,0 +1,234 @@
+//! Serde support, enabled with the `serde` feature.
+//! Human-readable formats such as JSON get hashes and node bytes as hex strings,
+//! while binary formats such as CBOR get them as raw bytes.
+//! `Proof` is written as `[right, cut]` pairs in human-readable formats,
+//! and as the bytes of `Proof::encode()` in binary formats.
+//!
+//! Roots and proofs embedded in other types can use the modules below with `#[serde(with)]`:
+//!
+//! ```
+//! use monotree::{Hash, RangeProof};
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Response {
+//!     #[serde(with = "monotree::serdes::option_hash")]
+//!     root: Option<Hash>,
+//!     #[serde(with = "monotree::serdes::nodes")]
+//!     proof: RangeProof,
+//! }
+//! ```
+use crate::proof::is_valid_cut;
+use crate::utils::*;
+use crate::*;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+
+impl Serialize for Proof {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let cuts: Vec<(bool, String)> = self
+                .iter()
+                .map(|(right, cut)| (*right, hex::encode(cut)))
+                .collect();
+            cuts.serialize(serializer)
+        } else {
+            let bytes = self.encode().map_err(serde::ser::Error::custom)?;
+            serializer.serialize_bytes(&bytes)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Proof {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let cuts: Vec<(bool, String)> = Deserialize::deserialize(deserializer)?;
+            let mut proof = Proof::default();
+            for (right, cut) in cuts {
+                let cut = hex::decode(cut).map_err(de::Error::custom)?;
+                if !is_valid_cut(right, &cut) {
+                    return Err(de::Error::custom("deserialize(): cut"));
+                }
+                proof.push((right, cut));
+            }
+            Ok(proof)
+        } else {
+            let bytes = deserializer.deserialize_byte_buf(BytesVisitor)?;
+            Proof::decode(&bytes).map_err(de::Error::custom)
+        }
+    }
+}
+
+/// `OwnedBits` as deserialized, before its range is checked against its path
+#[derive(Deserialize)]
+pub(crate) struct RawBits {
+    #[serde(with = "bytes")]
+    path: Vec<u8>,
+    range: std::ops::Range<BitsLen>,
+}
+
+impl TryFrom<RawBits> for OwnedBits {
+    type Error = Error;
+
+    /// The range must start within the first byte of the path and end within the path,
+    /// as `Bits::to_bytes()` requires.
+    fn try_from(raw: RawBits) -> Result<Self> {
+        let (start, end) = (raw.range.start, raw.range.end);
+        if start >= 8 || start >= end || raw.path.len() < nbytes_across(start, end) as usize {
+            return Err(Error::CorruptNode);
+        }
+        Ok(OwnedBits {
+            path: raw.path,
+            range: raw.range,
+        })
+    }
+}
+
+/// `OwnedNode` as deserialized, before its cells are checked
+#[derive(Deserialize)]
+pub(crate) enum RawNode {
+    Soft(Option<OwnedUnit>),
+    Hard(Option<OwnedUnit>, Option<OwnedUnit>),
+}
+
+impl TryFrom<RawNode> for OwnedNode {
+    type Error = Error;
+
+    /// A soft node has a unit, and a hard node has both, as read by `Node::from_bytes()`.
+    fn try_from(raw: RawNode) -> Result<Self> {
+        match raw {
+            RawNode::Soft(Some(unit)) => Ok(OwnedNode::Soft(Some(unit))),
+            RawNode::Hard(Some(lc), Some(rc)) => Ok(OwnedNode::Hard(Some(lc), Some(rc))),
+            _ => Err(Error::CorruptNode),
+        }
+    }
+}
+
+/// Visitor of bytes given either as a byte string or as a sequence of bytes
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> std::result::Result<Self::Value, E> {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> std::result::Result<Self::Value, E> {
+        Ok(v)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> std::result::Result<Self::Value, A::Error> {
+        let mut bytes = Vec::new();
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
+
+/// Bytes as hex in human-readable formats, and as raw bytes otherwise
+pub mod bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        bytes: &[u8],
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hex::encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let s: String = Deserialize::deserialize(deserializer)?;
+            hex::decode(s).map_err(de::Error::custom)
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+}
+
+/// `Hash` as in `bytes`, checking its length
+pub mod hash {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        hash: &Hash,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        bytes::serialize(hash, serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Hash, D::Error> {
+        let bytes = bytes::deserialize(deserializer)?;
+        if bytes.len() != HASH_LEN {
+            return Err(de::Error::invalid_length(bytes.len(), &"32 bytes"));
+        }
+        Ok(slice_to_hash(&bytes))
+    }
+}
+
+/// `Option<Hash>`, such as a root, as in `hash`
+pub mod option_hash {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Wrapper(#[serde(with = "hash")] Hash);
+
+    pub fn serialize<S: Serializer>(
+        hash: &Option<Hash>,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        hash.map(Wrapper).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Option<Hash>, D::Error> {
+        let wrapper: Option<Wrapper> = Deserialize::deserialize(deserializer)?;
+        Ok(wrapper.map(|Wrapper(hash)| hash))
+    }
+}
+
+/// List of node bytes, such as `RangeProof` and `MultiProof`, each as in `bytes`
+pub mod nodes {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Wrapper(#[serde(with = "bytes")] Vec<u8>);
+
+    #[derive(Serialize)]
+    struct WrapperRef<'a>(#[serde(with = "bytes")] &'a [u8]);
+
+    pub fn serialize<S: Serializer>(
+        nodes: &[Vec<u8>],
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_seq(nodes.iter().map(|node| WrapperRef(node)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Vec<Vec<u8>>, D::Error> {
+        let nodes: Vec<Wrapper> = Deserialize::deserialize(deserializer)?;
+        Ok(nodes.into_iter().map(|Wrapper(node)| node).collect())
+    }
+}
@@ -0,0 +1,78 @@
+use crate::*;
+use std::ops::Deref;
+use std::sync::MutexGuard;
+
+/// Example: How to read from a tree while it is being written
+/// ```
+/// use monotree::database::Sled;
+/// use monotree::utils::{random_bytes, random_hashes};
+/// use monotree::{tree, Result, SharedMonotree};
+/// use std::sync::Arc;
+/// use std::{env, fs, thread};
+///
+/// fn example(dbpath: &str) -> Result<()> {
+///     let shared = Arc::new(SharedMonotree::<Sled>::open(dbpath)?);
+///     let keys = random_hashes(100);
+///     let root = shared.writer().inserts(None, &keys, &keys)?;
+///
+///     // a reader proves against the root committed, while the writer goes on
+///     let reader = Arc::clone(&shared);
+///     let key = keys[0];
+///     let handle = thread::spawn(move || reader.get_merkle_proof(root.as_ref(), &key));
+///     shared.writer().removes(root.as_ref(), &keys[..50])?;
+///
+///     let proof = handle.join().unwrap()?;
+///     let hasher = &shared.hasher;
+///     assert!(tree::verify_proof(hasher, root.as_ref(), &key, proof.as_ref()));
+///     Ok(())
+/// }
+///
+/// // a database of its own, removed once done with
+/// let dbpath = env::temp_dir().join(format!("monotree-{}", hex::encode(random_bytes(4))));
+/// let dbpath = dbpath.to_str().unwrap();
+/// let result = example(dbpath);
+/// let _ = fs::remove_dir_all(dbpath);
+/// result.unwrap();
+/// ```
+impl<D, H> SharedMonotree<D, H>
+where
+    D: SharedDatabase,
+    H: Hasher,
+{
+    pub fn new(dbpath: &str) -> Self {
+        Self::from_tree(Monotree::new(dbpath)).expect("new(): shared monotree")
+    }
+
+    /// Fallible version of `new()`, returning an error if the database cannot be opened
+    pub fn open(dbpath: &str) -> Result<Self> {
+        Self::from_tree(Monotree::open(dbpath)?)
+    }
+
+    /// Share a tree: it becomes the writer, and the readers get a new handle to its database.
+    /// Returns an error if the database cannot be shared, as by `SharedDatabase::share()`.
+    pub fn from_tree(tree: Monotree<D, H>) -> Result<Self> {
+        Ok(SharedMonotree {
+            reader: Monotree::with_db(tree.db.share()?),
+            writer: Mutex::new(tree),
+        })
+    }
+
+    /// Lock the writer. Writers take turns, but readers never wait for them.
+    /// A root is readable once the writing call yielding it has returned,
+    /// since the batched writes (`inserts()`, `removes()`) reach the database all at once
+    /// when the call finishes its batch. Reading from a root pruned or released by the writer
+    /// is not to be relied on: the nodes may still be served from the readers' cache,
+    /// or be gone, failing with `Error::MissingNode`.
+    pub fn writer(&self) -> MutexGuard<'_, Monotree<D, H>> {
+        self.writer.lock().expect("writer(): monotree")
+    }
+}
+
+/// Readers use the `&self` methods of `Monotree`, such as `get()` and `get_merkle_proof()`
+impl<D, H> Deref for SharedMonotree<D, H> {
+    type Target = Monotree<D, H>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.reader
+    }
+}
@@ -1,5 +1,21 @@
+use crate::proof::is_valid_cut;
 use crate::utils::*;
 use crate::*;
+use hashbrown::HashMap;
+use std::ops::Bound;
+
+/// The number of deletions written in a single batch when pruning
+const PRUNE_BATCH_SIZE: usize = 1 << 12;
+
+/// What is left of a node after removing a key from under it
+enum Deleted {
+    /// The key is not under the node, which is left as it is
+    Absent,
+    /// The node rewritten, or `None` if nothing is left under it
+    Node(Option<Hash>),
+    /// The only unit left in the node, which is not put
+    Unit(OwnedUnit),
+}
 
 impl Default for Monotree<DefaultDatabase, DefaultHasher> {
     fn default() -> Self {
@@ -49,18 +65,50 @@ where
     H: Hasher,
 {
     pub fn new(dbpath: &str) -> Self {
-        let db = Database::new(dbpath);
+        Self::with_db(Database::new(dbpath))
+    }
+
+    /// Fallible version of `new()`, returning an error if the database cannot be opened
+    pub fn open(dbpath: &str) -> Result<Self> {
+        Ok(Self::with_db(Database::open(dbpath)?))
+    }
+
+    /// Build a tree on top of a database already opened,
+    /// such as `database::RawRocksDB::open_with()` or `database::RawSled::from_db()`
+    pub fn with_db(db: D) -> Self {
         let hasher = Hasher::new();
         Monotree { db, hasher }
     }
 
-    pub fn insert(&mut self, root: Option<&Hash>, key: &Hash, leaf: &Hash) -> Result<Option<Hash>> {
+    /// The database under the tree, such as to read the statistics of its cache
+    pub fn db(&self) -> &D {
+        &self.db
+    }
+
+    /// The database under the tree, mutably, such as to compact it
+    pub fn db_mut(&mut self) -> &mut D {
+        &mut self.db
+    }
+
+    /// Insert an entry of key and leaf into the tree, yielding a new root.
+    /// Keys can be of any length: they go into the tree in the prefix-free form
+    /// given by `utils::encode_key()`, so that a key can be a prefix of another.
+    pub fn insert(&mut self, root: Option<&Hash>, key: &[u8], leaf: &Hash) -> Result<Option<Hash>> {
+        let key = encode_key(key)?;
         match root {
             None => {
-                let (hash, bits) = (leaf, Bits::new(key));
+                let (hash, bits) = (leaf, Bits::new(&key));
                 self.put_node(Node::new(Some(Unit { hash, bits }), None))
             }
-            Some(root) => self.put(root, Bits::new(key), leaf),
+            Some(root) => self.put(root, Bits::new(&key), leaf),
+        }
+    }
+
+    /// Get the bytes of a node, failing if the node is not in the database
+    pub(crate) fn get_node(&self, hash: &[u8]) -> Result<Vec<u8>> {
+        match self.db.get(hash)? {
+            Some(bytes) => Ok(bytes),
+            None => Err(Error::MissingNode(slice_to_hash(hash))),
         }
     }
 
@@ -84,9 +132,9 @@ where
     /// - (2) split-node: immideately split node into two with the logest common prefix, then wind recursive stack.
     /// the number in parenthesis refers to the minimum of DB access and hash fn call required.
     fn put(&mut self, root: &[u8], bits: Bits, leaf: &[u8]) -> Result<Option<Hash>> {
-        let bytes = self.db.get(root)?.expect("bytes");
+        let bytes = self.get_node(root)?;
         let (lc, rc) = Node::cells_from_bytes(&bytes, bits.first())?;
-        let unit = lc.as_ref().expect("put(): left-unit");
+        let unit = lc.as_ref().ok_or(Error::CorruptNode)?;
         let n = Bits::len_common_bits(&unit.bits, &bits);
         match n {
             n if n == 0 => self.put_node(Node::new(lc, Some(Unit { hash: leaf, bits }))),
@@ -94,7 +142,7 @@ where
             n if n == unit.bits.len() => {
                 let hash = &self
                     .put(unit.hash, bits.shift(n, false), leaf)?
-                    .expect("put(): hash");
+                    .ok_or(Error::CorruptNode)?;
                 let unit = unit.to_owned();
                 self.put_node(Node::new(Some(Unit { hash, ..unit }), rc))
             }
@@ -108,24 +156,24 @@ where
 
                 let hash = &self
                     .put_node(Node::new(Some(lu), Some(ru)))?
-                    .expect("put(): hash");
+                    .ok_or(Error::CorruptNode)?;
                 let bits = cloned.shift(n, true);
                 self.put_node(Node::new(Some(Unit { hash, bits }), rc))
             }
         }
     }
 
-    pub fn get(&mut self, root: Option<&Hash>, key: &Hash) -> Result<Option<Hash>> {
+    pub fn get(&self, root: Option<&Hash>, key: &[u8]) -> Result<Option<Hash>> {
         match root {
             None => Ok(None),
-            Some(root) => self.find_key(root, Bits::new(key)),
+            Some(root) => self.find_key(root, Bits::new(&encode_key(key)?)),
         }
     }
 
-    fn find_key(&mut self, root: &[u8], bits: Bits) -> Result<Option<Hash>> {
-        let bytes = self.db.get(root)?.expect("bytes");
+    fn find_key(&self, root: &[u8], bits: Bits) -> Result<Option<Hash>> {
+        let bytes = self.get_node(root)?;
         let (cell, _) = Node::cells_from_bytes(&bytes, bits.first())?;
-        let unit = cell.as_ref().expect("find_key(): left-unit");
+        let unit = cell.as_ref().ok_or(Error::CorruptNode)?;
         let n = Bits::len_common_bits(&unit.bits, &bits);
         match n {
             n if n == bits.len() => Ok(Some(slice_to_hash(unit.hash))),
@@ -134,78 +182,232 @@ where
         }
     }
 
+    /// Remove the entry of a key from the tree, yielding a new root.
+    /// The root is left as it is if the key is not in the tree.
     pub fn remove(&mut self, root: Option<&Hash>, key: &[u8]) -> Result<Option<Hash>> {
-        match root {
-            None => Ok(None),
-            Some(root) => self.delete_key(root, Bits::new(key)),
+        let root = match root {
+            None => return Ok(None),
+            Some(root) => root,
+        };
+        match self.delete_key(root, Bits::new(&encode_key(key)?))? {
+            Deleted::Absent => Ok(Some(*root)),
+            Deleted::Node(hash) => Ok(hash),
+            Deleted::Unit(unit) => self.put_node(Node::new(Some(unit.as_unit()), None)),
         }
     }
 
-    fn delete_key(&mut self, root: &[u8], bits: Bits) -> Result<Option<Hash>> {
-        let bytes = self.db.get(root)?.expect("bytes");
+    /// Recursively remove a key from under a node.
+    /// A node left with a single unit is not put: the unit goes up to be joined
+    /// with the unit pointing to the node, or becomes the only unit of the root.
+    /// Thus, the tree keeps the shape `put()` would give it with the keys left,
+    /// whatever keys were inserted and removed before.
+    fn delete_key(&mut self, root: &[u8], bits: Bits) -> Result<Deleted> {
+        let bytes = self.get_node(root)?;
         let (lc, rc) = Node::cells_from_bytes(&bytes, bits.first())?;
-        let unit = lc.as_ref().expect("delete_key(): left-unit");
+        let unit = lc.as_ref().ok_or(Error::CorruptNode)?;
         let n = Bits::len_common_bits(&unit.bits, &bits);
-        match n {
-            n if n == bits.len() => match rc {
-                Some(_) => self.put_node(Node::new(None, rc)),
-                None => Ok(None),
+        let lc = match n {
+            n if n == bits.len() => None,
+            n if n == unit.bits.len() => match self.delete_key(unit.hash, bits.shift(n, false))? {
+                Deleted::Absent => return Ok(Deleted::Absent),
+                Deleted::Node(None) => None,
+                Deleted::Node(Some(hash)) => Some(OwnedUnit {
+                    hash,
+                    bits: OwnedBits::from(&unit.bits),
+                }),
+                Deleted::Unit(lower) => Some(OwnedUnit {
+                    hash: lower.hash,
+                    bits: OwnedBits::join(&unit.bits, &lower.bits.as_bits())?,
+                }),
             },
-            n if n == unit.bits.len() => {
-                let hash = self.delete_key(&unit.hash, bits.shift(n, false))?;
-                match (hash, &rc) {
-                    (None, None) => Ok(None),
-                    (None, Some(_)) => self.put_node(Node::new(None, rc)),
-                    (Some(ref hash), _) => {
-                        let unit = unit.to_owned();
-                        let lc = Some(Unit { hash, ..unit });
-                        self.put_node(Node::new(lc, rc))
-                    }
-                }
+            _ => return Ok(Deleted::Absent),
+        };
+        match (lc, rc) {
+            (None, None) => Ok(Deleted::Node(None)),
+            (None, Some(rc)) => Ok(Deleted::Unit(OwnedUnit::from(&rc))),
+            (Some(lc), rc) => {
+                let hash = self.put_node(Node::new(Some(lc.as_unit()), rc))?;
+                Ok(Deleted::Node(hash))
             }
-            _ => Ok(None),
+        }
+    }
+
+    /// Value-store mode: insert a value of arbitrary length, instead of a leaf.
+    /// The value is stored content-addressed in the same database,
+    /// and its hash goes into the tree as the leaf of the key.
+    /// The value and the nodes of the key are written in a single batch.
+    /// Thus, Merkle proofs work over the hash of the value.
+    ///
+    /// ```
+    /// use monotree::tree::verify_proof;
+    /// use monotree::utils::{random_bytes, random_hash};
+    /// use monotree::hasher::Blake3;
+    /// use monotree::{Hasher, Monotree, Result};
+    ///
+    /// fn example() -> Result<()> {
+    ///     let mut tree = Monotree::default();
+    ///     let key = random_hash();
+    ///     let value = random_bytes(300);
+    ///     let root = tree.insert_value(None, &key, &value)?;
+    ///     assert_eq!(tree.get_value(root.as_ref(), &key)?, Some(value.to_owned()));
+    ///
+    ///     // the leaf is the hash of the value
+    ///     let hasher = Blake3::new();
+    ///     let leaf = hasher.digest(&value);
+    ///     let proof = tree.get_merkle_proof(root.as_ref(), &key)?;
+    ///     assert_eq!(verify_proof(&hasher, root.as_ref(), &leaf, proof.as_ref()), true);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn insert_value(
+        &mut self,
+        root: Option<&Hash>,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<Hash>> {
+        let leaf = self.hasher.digest(value);
+        self.db.init_batch()?;
+        self.db.put(&leaf, value.to_vec())?;
+        let root = self.insert(root, key, &leaf)?;
+        self.db.finish_batch()?;
+        Ok(root)
+    }
+
+    /// Get the value inserted by `insert_value()`
+    pub fn get_value(&self, root: Option<&Hash>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.get(root, key)? {
+            None => Ok(None),
+            Some(leaf) => self.db.get(&leaf),
         }
     }
 
     /// This method is for batch use of `insert()` method
     /// input: slice of each keys and leaves.
-    pub fn inserts(
+    /// The keys go down the tree all together, rather than one by one,
+    /// and the subtrees apart from each other are built in parallel with the `rayon` feature.
+    /// The root is the same as given by `insert()` with each key in order.
+    pub fn inserts<K: AsRef<[u8]> + Clone + Ord>(
         &mut self,
         root: Option<&Hash>,
-        keys: &[Hash],
+        keys: &[K],
         leaves: &[Hash],
     ) -> Result<Option<Hash>> {
         let indices = get_sorted_indices(keys, false);
-        self.db.init_batch()?;
-        let mut root = root.cloned();
-        for i in indices.iter() {
-            root = self.insert(root.as_ref(), &keys[*i], &leaves[*i])?;
-        }
-        self.db.finish_batch()?;
-        Ok(root)
+        let encoded = indices
+            .iter()
+            .map(|i| encode_key(keys[*i].as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+        let leaves: Vec<Option<&Hash>> = indices.iter().map(|i| Some(&leaves[*i])).collect();
+        self.update_sorted(root, &encoded, &leaves)
+    }
+
+    /// Insert and remove many keys in a single pass, yielding a new root.
+    /// Each entry is a key with `Some(leaf)` to insert, or `None` to remove it.
+    /// The entries go down the tree all together in order of keys,
+    /// so that each node touched is rebuilt, hashed and written only once.
+    /// Of the entries with the same key, the last one given wins.
+    /// Removing a key not in the tree has no effect.
+    ///
+    /// ```
+    /// use monotree::utils::random_hashes;
+    /// use monotree::{Monotree, Result};
+    ///
+    /// fn example() -> Result<()> {
+    ///     let mut tree = Monotree::default();
+    ///     let keys = random_hashes(3);
+    ///     let leaves = random_hashes(3);
+    ///     let root = tree.inserts(None, &keys[..2], &leaves[..2])?;
+    ///
+    ///     // remove the first key and insert the last one at once
+    ///     let root = tree.update(root.as_ref(), &[(keys[0], None), (keys[2], Some(leaves[2]))])?;
+    ///     assert_eq!(tree.get(root.as_ref(), &keys[0])?, None);
+    ///     assert_eq!(tree.get(root.as_ref(), &keys[1])?, Some(leaves[1]));
+    ///     assert_eq!(tree.get(root.as_ref(), &keys[2])?, Some(leaves[2]));
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn update<K: AsRef<[u8]>>(
+        &mut self,
+        root: Option<&Hash>,
+        entries: &[(K, Option<Hash>)],
+    ) -> Result<Option<Hash>> {
+        let mut indices: Vec<usize> = (0..entries.len()).collect();
+        indices.sort_by(|&a, &b| entries[a].0.as_ref().cmp(entries[b].0.as_ref()));
+        let keys = indices
+            .iter()
+            .map(|i| encode_key(entries[*i].0.as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+        let leaves: Vec<Option<&Hash>> = indices.iter().map(|i| entries[*i].1.as_ref()).collect();
+        self.update_sorted(root, &keys, &leaves)
+    }
+
+    /// Apply a changeset of operations in order, yielding the final root.
+    /// The whole changeset goes into the database in a single batch,
+    /// so that no intermediate root is ever written. See `update()`.
+    ///
+    /// ```
+    /// use monotree::utils::random_hashes;
+    /// use monotree::{Monotree, Op, Result};
+    ///
+    /// fn example() -> Result<()> {
+    ///     let mut tree = Monotree::default();
+    ///     let keys = random_hashes(2);
+    ///     let leaves = random_hashes(2);
+    ///     let changeset = [
+    ///         Op::Put(keys[0], leaves[0]),
+    ///         Op::Put(keys[1], leaves[1]),
+    ///         Op::Delete(keys[0]),
+    ///     ];
+    ///     let root = tree.apply(None, &changeset)?;
+    ///     assert_eq!(tree.get(root.as_ref(), &keys[0])?, None);
+    ///     assert_eq!(tree.get(root.as_ref(), &keys[1])?, Some(leaves[1]));
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn apply<K: AsRef<[u8]>>(
+        &mut self,
+        root: Option<&Hash>,
+        changeset: &[Op<K>],
+    ) -> Result<Option<Hash>> {
+        let entries: Vec<(&[u8], Option<Hash>)> = changeset
+            .iter()
+            .map(|op| match op {
+                Op::Put(key, leaf) => (key.as_ref(), Some(*leaf)),
+                Op::Delete(key) => (key.as_ref(), None),
+            })
+            .collect();
+        self.update(root, &entries)
     }
 
     /// This method is for batch use of `get()` method
     /// output: vector of leaves retrieved
-    pub fn gets(&mut self, root: Option<&Hash>, keys: &[Hash]) -> Result<Vec<Option<Hash>>> {
+    pub fn gets<K: AsRef<[u8]>>(
+        &self,
+        root: Option<&Hash>,
+        keys: &[K],
+    ) -> Result<Vec<Option<Hash>>> {
         let mut leaves: Vec<Option<Hash>> = Vec::new();
         for key in keys.iter() {
-            leaves.push(self.get(root, key)?);
+            leaves.push(self.get(root, key.as_ref())?);
         }
         Ok(leaves)
     }
 
     /// This method is for batch use of `remove()` method
     /// input: slice of each keys and leaves.
-    pub fn removes(&mut self, root: Option<&Hash>, keys: &[Hash]) -> Result<Option<Hash>> {
+    /// The keys are removed in a single pass as `update()` does,
+    /// where the keys not in the tree are left out.
+    pub fn removes<K: AsRef<[u8]> + Clone + Ord>(
+        &mut self,
+        root: Option<&Hash>,
+        keys: &[K],
+    ) -> Result<Option<Hash>> {
         let indices = get_sorted_indices(keys, false);
-        let mut root = root.cloned();
-        self.db.init_batch()?;
-        for i in indices.iter() {
-            root = self.remove(root.as_ref(), &keys[*i])?;
-        }
-        self.db.finish_batch()?;
-        Ok(root)
+        let encoded = indices
+            .iter()
+            .map(|i| encode_key(keys[*i].as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+        self.update_sorted(root, &encoded, &vec![None; encoded.len()])
     }
 
     /// `Merkle proof` secion: verifying inclusion of data (inclusion proof)
@@ -250,18 +452,18 @@ where
     ///     Ok(())
     /// }
     /// ```
-    pub fn get_merkle_proof(&mut self, root: Option<&Hash>, key: &[u8]) -> Result<Option<Proof>> {
-        let mut proof: Proof = Vec::new();
+    pub fn get_merkle_proof(&self, root: Option<&Hash>, key: &[u8]) -> Result<Option<Proof>> {
+        let mut proof = Proof::default();
         match root {
             None => Ok(None),
-            Some(root) => self.gen_proof(root, Bits::new(key), &mut proof),
+            Some(root) => self.gen_proof(root, Bits::new(&encode_key(key)?), &mut proof),
         }
     }
 
-    fn gen_proof(&mut self, root: &[u8], bits: Bits, proof: &mut Proof) -> Result<Option<Proof>> {
-        let bytes = self.db.get(root)?.expect("bytes");
+    fn gen_proof(&self, root: &[u8], bits: Bits, proof: &mut Proof) -> Result<Option<Proof>> {
+        let bytes = self.get_node(root)?;
         let (cell, _) = Node::cells_from_bytes(&bytes, bits.first())?;
-        let unit = cell.as_ref().expect("gen_proof(): left-unit");
+        let unit = cell.as_ref().ok_or(Error::CorruptNode)?;
         let n = Bits::len_common_bits(&unit.bits, &bits);
         match n {
             n if n == bits.len() => {
@@ -291,6 +493,273 @@ where
             }
         }
     }
+
+    /// `Non-inclusion proof` section: verifying absence of data
+    /// ---------------------------------------------------------
+    /// Walking down the tree with a key tells us the key is absent,
+    /// but a light client holding only a root cannot walk the tree.
+    /// Instead, this gives it the Merkle path to the node where the key's path
+    /// diverges from the tree: either the unit on the path goes in another direction
+    /// or the side of a `Node::Soft` that the key needs is empty.
+    /// It returns `None` if the key is present in the tree.
+    ///
+    /// ```
+    /// use monotree::tree::verify_non_inclusion;
+    /// use monotree::utils::{random_hash, random_hashes};
+    /// use monotree::hasher::Blake3;
+    /// use monotree::{Hasher, Monotree, Result};
+    ///
+    /// fn example() -> Result<()> {
+    ///     let mut tree = Monotree::default();
+    ///     let keys = random_hashes(500);
+    ///     let leaves = random_hashes(500);
+    ///     let root = tree.inserts(None, &keys, &leaves)?;
+    ///
+    ///     // a key never inserted into the tree
+    ///     let key = random_hash();
+    ///     let proof = tree.get_non_inclusion_proof(root.as_ref(), &key)?;
+    ///
+    ///     // verify the absence of the key with the root only
+    ///     let hasher = Blake3::new();
+    ///     let verified = verify_non_inclusion(&hasher, root.as_ref(), &key, proof.as_ref());
+    ///     assert_eq!(verified, true);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn get_non_inclusion_proof(
+        &self,
+        root: Option<&Hash>,
+        key: &[u8],
+    ) -> Result<Option<NonInclusionProof>> {
+        let mut path = Proof::default();
+        match root {
+            None => Ok(Some(NonInclusionProof {
+                path,
+                node: Vec::new(),
+            })),
+            Some(root) => {
+                let key = encode_key(key)?;
+                self.gen_non_inclusion_proof(root, Bits::new(&key), &mut path)
+            }
+        }
+    }
+
+    fn gen_non_inclusion_proof(
+        &self,
+        root: &[u8],
+        bits: Bits,
+        path: &mut Proof,
+    ) -> Result<Option<NonInclusionProof>> {
+        let bytes = self.get_node(root)?;
+        let (cell, _) = Node::cells_from_bytes(&bytes, bits.first())?;
+        let unit = cell.as_ref().ok_or(Error::CorruptNode)?;
+        let n = Bits::len_common_bits(&unit.bits, &bits);
+        match n {
+            n if n == bits.len() => Ok(None),
+            n if n == unit.bits.len() => {
+                path.push(self.encode_proof(&bytes, bits.first())?);
+                self.gen_non_inclusion_proof(unit.hash, bits.shift(n, false), path)
+            }
+            _ => Ok(Some(NonInclusionProof {
+                path: path.to_owned(),
+                node: bytes,
+            })),
+        }
+    }
+
+    /// `Range proof` section: verifying completeness of entries in a range
+    /// --------------------------------------------------------------------
+    /// The proof is made of the nodes on the paths to the bounds of `[start, end)`,
+    /// those whose subtrees are partly in the range and partly out of it.
+    /// Every subtree out of the range is left as a bare hash in its parent,
+    /// and every subtree within the range is left for the verifier to rebuild from the entries,
+    /// since the tree has a single shape for a given set of keys.
+    /// Thus, the proof grows with the depth of the tree, not with the number of entries.
+    /// An entry left out, or one added, makes the rebuilt root differ.
+    ///
+    /// ```
+    /// use monotree::tree::verify_range_proof;
+    /// use monotree::utils::random_hashes;
+    /// use monotree::hasher::Blake3;
+    /// use monotree::{Hasher, Monotree, Result};
+    ///
+    /// fn example() -> Result<()> {
+    ///     let mut tree = Monotree::default();
+    ///     let mut keys = random_hashes(500);
+    ///     let leaves = random_hashes(500);
+    ///     let root = tree.inserts(None, &keys, &leaves)?;
+    ///
+    ///     keys.sort();
+    ///     let (start, end) = (keys[100], keys[200]);
+    ///     let entries = tree
+    ///         .range(root.as_ref(), start..end)?
+    ///         .collect::<Result<Vec<_>>>()?;
+    ///     let proof = tree.get_range_proof(root.as_ref(), &start, &end)?;
+    ///
+    ///     let hasher = Blake3::new();
+    ///     let verified = verify_range_proof(&hasher, root.as_ref(), &start, &end, &entries, &proof);
+    ///     assert_eq!(verified, true);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn get_range_proof(
+        &self,
+        root: Option<&Hash>,
+        start: &[u8],
+        end: &[u8],
+    ) -> Result<RangeProof> {
+        let mut proof: RangeProof = Vec::new();
+        let lower = Bound::Included(bytes_to_bits(&encode_key(start)?));
+        let upper = Bound::Excluded(bytes_to_bits(&encode_key(end)?));
+        let take = |path: &[bool]| take_in_range(path, &lower, &upper);
+        if let Some(root) = root {
+            self.gen_partial_tree(root, &[], &take, &mut proof)?;
+        }
+        Ok(proof)
+    }
+
+    /// `Multi-proof` section: verifying inclusion of many keys at once
+    /// ----------------------------------------------------------------
+    /// Merkle proofs of keys under the same root repeat the upper nodes of the tree.
+    /// A multi-proof puts each node on the paths to the keys only once,
+    /// and a verifier checks all of the keys in one pass.
+    /// It returns `None` if any of the keys is absent from the tree.
+    ///
+    /// ```
+    /// use monotree::tree::verify_multi_proof;
+    /// use monotree::utils::random_hashes;
+    /// use monotree::hasher::Blake3;
+    /// use monotree::{Hasher, Monotree, Result};
+    ///
+    /// fn example() -> Result<()> {
+    ///     let mut tree = Monotree::default();
+    ///     let keys = random_hashes(500);
+    ///     let leaves = random_hashes(500);
+    ///     let root = tree.inserts(None, &keys, &leaves)?;
+    ///     let proof = tree.get_multi_proof(root.as_ref(), &keys[..100])?;
+    ///
+    ///     let hasher = Blake3::new();
+    ///     let entries: Vec<_> = keys.iter().zip(leaves.iter()).map(|(k, v)| (*k, *v)).collect();
+    ///     let verified = verify_multi_proof(&hasher, root.as_ref(), &entries[..100], proof.as_ref());
+    ///     assert_eq!(verified, true);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn get_multi_proof<K: AsRef<[u8]>>(
+        &self,
+        root: Option<&Hash>,
+        keys: &[K],
+    ) -> Result<Option<MultiProof>> {
+        let root = match root {
+            None => return Ok(None),
+            Some(root) => root,
+        };
+        let mut paths = keys
+            .iter()
+            .map(|key| Ok(bytes_to_bits(&encode_key(key.as_ref())?)))
+            .collect::<Result<Vec<_>>>()?;
+        paths.sort();
+        paths.dedup();
+        let mut proof: MultiProof = Vec::new();
+        let take = |path: &[bool]| take_on_paths(path, &paths);
+        match self.gen_partial_tree(root, &[], &take, &mut proof)? {
+            n if n == paths.len() => Ok(Some(proof)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Put the node and then the nodes under its units taken by `Take::Descend` in pre-order,
+    /// zeroing out the hashes of the units but those taken by `Take::Hash`.
+    /// Returns the number of units taken by `Take::Rebuild`.
+    fn gen_partial_tree(
+        &self,
+        root: &Hash,
+        path: &[bool],
+        take: &dyn Fn(&[bool]) -> Take,
+        proof: &mut Vec<Vec<u8>>,
+    ) -> Result<usize> {
+        let mut bytes = self.get_node(root)?;
+        let mut next = Vec::new();
+        {
+            let (lc, rc) = Node::cells_from_bytes(&bytes, false)?;
+            let offsets = [0, bytes.len() - HASH_LEN - 1];
+            for (cell, &i) in [lc, rc].iter().zip(offsets.iter()) {
+                if let Some(unit) = cell {
+                    let path = join_path(path, &unit.bits)?;
+                    match take(&path) {
+                        Take::Hash => {}
+                        taken => next.push((i, slice_to_hash(unit.hash), path, taken)),
+                    }
+                }
+            }
+        }
+        for (i, _, _, _) in next.iter() {
+            bytes[*i..*i + HASH_LEN].copy_from_slice(&[0x00; HASH_LEN]);
+        }
+        proof.push(bytes);
+        let mut n = 0;
+        for (_, hash, path, taken) in next.iter() {
+            match taken {
+                Take::Descend => n += self.gen_partial_tree(hash, path, take, proof)?,
+                _ => n += 1,
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Pruning, with a database whose keys can be listed
+impl<D, H> Monotree<D, H>
+where
+    D: IterableDatabase,
+    H: Hasher,
+{
+    /// Garbage-collect the database: mark every node reachable from `keep_roots`,
+    /// then sweep all the other nodes off the database in batches.
+    /// Since every update writes new nodes and never deletes the old ones,
+    /// this is the way to take back the space used by the roots no longer needed.
+    /// Be aware of that any root not given here is no longer available after this.
+    /// As the listing of keys borrows the database, the garbage is taken
+    /// `PRUNE_BATCH_SIZE` keys at a time from a listing begun anew,
+    /// and deleted before the next, so that it is never all held in memory.
+    /// Returns the number of nodes deleted.
+    pub fn prune(&mut self, keep_roots: &[Hash]) -> Result<usize> {
+        let live = database::mark(&self.db, keep_roots)?;
+        let mut deleted = 0;
+        loop {
+            let garbage = self
+                .db
+                .keys()?
+                .filter(|key| !matches!(key, Ok(key) if live.contains(key)))
+                .take(PRUNE_BATCH_SIZE)
+                .collect::<Result<Vec<Hash>>>()?;
+            if garbage.is_empty() {
+                return Ok(deleted);
+            }
+            self.db.init_batch()?;
+            for key in garbage.iter() {
+                self.db.delete(key)?;
+            }
+            self.db.finish_batch()?;
+            deleted += garbage.len();
+        }
+    }
+}
+
+/// Incremental pruning with a reference-counted database.
+/// See `database::RefCounted` for details.
+impl<D, H> Monotree<database::RefCounted<D>, H>
+where
+    D: Database,
+    H: Hasher,
+{
+    pub fn retain(&mut self, root: &Hash) -> Result<()> {
+        self.db.retain(root)
+    }
+
+    pub fn release(&mut self, root: &Hash) -> Result<()> {
+        self.db.release(root)
+    }
 }
 
 /// Verify a Merkle proof with the given root, leaf and hasher
@@ -302,21 +771,418 @@ pub fn verify_proof<H: Hasher>(
     leaf: &Hash,
     proof: Option<&Proof>,
 ) -> bool {
-    match proof {
-        None => false,
-        Some(proof) => {
-            let mut hash = leaf.to_owned();
-            proof.iter().rev().for_each(|(right, cut)| {
-                if *right {
-                    let l = cut.len();
-                    let o = [&cut[..l - 1], &hash[..], &cut[l - 1..]].concat();
-                    hash = hasher.digest(&o);
-                } else {
-                    let o = [&hash[..], &cut[..]].concat();
-                    hash = hasher.digest(&o);
+    match (root, proof) {
+        (Some(root), Some(proof)) => fold_proof(hasher, leaf, proof).as_ref() == Some(root),
+        _ => false,
+    }
+}
+
+/// Verify a Merkle proof with the given root, key, leaf and hasher
+/// Unlike `verify_proof()`, this binds the proof to the key as well:
+/// the bits segments in the cuts must join up to the exact key queried,
+/// so that a proof for a key cannot be replayed for another key.
+///
+/// ```
+/// use monotree::tree::verify_proof_for_key;
+/// use monotree::utils::random_hashes;
+/// use monotree::hasher::Blake3;
+/// use monotree::{Hasher, Monotree, Result};
+///
+/// fn example() -> Result<()> {
+///     let mut tree = Monotree::default();
+///     let keys = random_hashes(500);
+///     let leaves = random_hashes(500);
+///     let root = tree.inserts(None, &keys, &leaves)?;
+///     let proof = tree.get_merkle_proof(root.as_ref(), &keys[99])?;
+///
+///     let hasher = Blake3::new();
+///     let verified = verify_proof_for_key(&hasher, root.as_ref(), &keys[99], &leaves[99], proof.as_ref());
+///     assert_eq!(verified, true);
+///
+///     // the same proof never holds for another key
+///     let verified = verify_proof_for_key(&hasher, root.as_ref(), &keys[0], &leaves[99], proof.as_ref());
+///     assert_eq!(verified, false);
+///     Ok(())
+/// }
+/// ```
+pub fn verify_proof_for_key<H: Hasher>(
+    hasher: &H,
+    root: Option<&Hash>,
+    key: &[u8],
+    leaf: &Hash,
+    proof: Option<&Proof>,
+) -> bool {
+    match (proof, encode_key(key)) {
+        (Some(proof), Ok(key)) => {
+            len_path_on_key(proof, &key) == Some(Bits::new(&key).len())
+                && verify_proof(hasher, root, leaf, Some(proof))
+        }
+        _ => false,
+    }
+}
+
+/// Verify a non-inclusion proof with the given root, key and hasher
+/// The path in the proof must follow the key and end up with the node
+/// where the key diverges from the tree. No database access is needed.
+/// An empty tree, or `None` root, does not include any key.
+pub fn verify_non_inclusion<H: Hasher>(
+    hasher: &H,
+    root: Option<&Hash>,
+    key: &[u8],
+    proof: Option<&NonInclusionProof>,
+) -> bool {
+    let key = match encode_key(key) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    match (root, proof) {
+        (_, None) => false,
+        (None, Some(_)) => true,
+        (Some(root), Some(proof)) => {
+            let hash = hasher.digest(&proof.node);
+            if fold_proof(hasher, &hash, &proof.path).as_ref() != Some(root) {
+                return false;
+            }
+            let n = match len_path_on_key(&proof.path, &key) {
+                Some(n) if n < Bits::new(&key).len() => n,
+                _ => return false,
+            };
+            let bits = Bits::new(&key).shift(n, false);
+            match Node::cells_from_bytes(&proof.node, bits.first()) {
+                Ok((Some(unit), _)) => {
+                    let n = Bits::len_common_bits(&unit.bits, &bits);
+                    n < unit.bits.len() && n < bits.len()
                 }
-            });
-            root.expect("verify_proof(): root") == &hash
+                _ => false,
+            }
+        }
+    }
+}
+
+/// Verify a range proof with the given root, range `[start, end)`, entries and hasher
+/// The entries must be all of the `(key, leaf)` pairs in the range, in the order of keys,
+/// as yielded by `Monotree::range()`. No database access is needed.
+pub fn verify_range_proof<H: Hasher>(
+    hasher: &H,
+    root: Option<&Hash>,
+    start: &[u8],
+    end: &[u8],
+    entries: &[(Vec<u8>, Hash)],
+    proof: &RangeProof,
+) -> bool {
+    let (lower, upper) = match (encode_key(start), encode_key(end)) {
+        (Ok(start), Ok(end)) => (
+            Bound::Included(bytes_to_bits(&start)),
+            Bound::Excluded(bytes_to_bits(&end)),
+        ),
+        _ => return false,
+    };
+    let root = match root {
+        None => return entries.is_empty() && proof.is_empty(),
+        Some(root) => root,
+    };
+    let keys = match entries
+        .iter()
+        .map(|(key, leaf)| Ok((encode_key(key)?, *leaf)))
+        .collect::<Result<Vec<_>>>()
+    {
+        Ok(keys) => keys,
+        Err(_) => return false,
+    };
+    if keys.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+        return false;
+    }
+    let take = |path: &[bool]| take_in_range(path, &lower, &upper);
+    let mut i = 0;
+    let mut rebuild = |path: &[bool]| {
+        let n = keys[i..]
+            .iter()
+            .take_while(|(key, _)| starts_with(key, path))
+            .count();
+        let under = &keys[i..i + n];
+        i += n;
+        match (is_complete_key(path), n) {
+            (true, 1) => Some(under[0].1),
+            (false, n) if n > 1 => rebuild_subtree(hasher, under, path.len()),
+            _ => None,
+        }
+    };
+    let mut nodes = proof.iter();
+    let hash = fold_partial_tree(hasher, &take, &mut nodes, &mut rebuild);
+    hash.as_ref() == Some(root) && nodes.next().is_none() && i == keys.len()
+}
+
+/// Verify a multi-proof with the given root, `(key, leaf)` pairs and hasher
+/// Every node in the proof must lie on the path to one of the keys,
+/// and every key must be reached. No database access is needed.
+pub fn verify_multi_proof<K: AsRef<[u8]>, H: Hasher>(
+    hasher: &H,
+    root: Option<&Hash>,
+    entries: &[(K, Hash)],
+    proof: Option<&MultiProof>,
+) -> bool {
+    let (root, proof) = match (root, proof) {
+        (Some(root), Some(proof)) => (root, proof),
+        _ => return false,
+    };
+    let mut leaves: HashMap<Vec<bool>, Hash> = HashMap::new();
+    for (key, leaf) in entries.iter() {
+        let path = match encode_key(key.as_ref()) {
+            Ok(key) => bytes_to_bits(&key),
+            Err(_) => return false,
+        };
+        match leaves.insert(path, *leaf) {
+            Some(other) if &other != leaf => return false,
+            _ => {}
+        }
+    }
+    let mut paths: Vec<Vec<bool>> = leaves.keys().cloned().collect();
+    paths.sort();
+    let take = |path: &[bool]| take_on_paths(path, &paths);
+    let mut leaf = |path: &[bool]| leaves.remove(path);
+    let mut nodes = proof.iter();
+    let hash = fold_partial_tree(hasher, &take, &mut nodes, &mut leaf);
+    hash.as_ref() == Some(root) && nodes.next().is_none() && leaves.is_empty()
+}
+
+/// How a proof over a part of the tree takes a unit, by the path down to it
+#[derive(Clone, Copy)]
+enum Take {
+    /// Left as the hash in its node
+    Hash,
+    /// Rebuilt by the verifier from the entries under it
+    Rebuild,
+    /// Rebuilt from the node under it, which follows in the proof
+    Descend,
+}
+
+/// Take the units on the paths to the keys of a multi-proof: the leaves are given
+fn take_on_paths(path: &[bool], paths: &[Vec<bool>]) -> Take {
+    let i = match paths.binary_search_by(|x| x[..].cmp(path)) {
+        Ok(i) | Err(i) => i,
+    };
+    match paths.get(i) {
+        Some(x) if x.starts_with(path) && is_complete_key(path) => Take::Rebuild,
+        Some(x) if x.starts_with(path) => Take::Descend,
+        _ => Take::Hash,
+    }
+}
+
+/// Take the units of a range proof: the entries in the range are given,
+/// so that only the units partly in the range go on down the proof
+fn take_in_range(path: &[bool], lower: &Bound<Vec<bool>>, upper: &Bound<Vec<bool>>) -> Take {
+    if !iter::is_within(path, lower, upper, &[]) {
+        Take::Hash
+    } else if is_complete_key(path) || iter::is_covered(path, lower, upper) {
+        Take::Rebuild
+    } else {
+        Take::Descend
+    }
+}
+
+/// Tell if the bits of an encoded key start with the path
+fn starts_with(key: &[u8], path: &[bool]) -> bool {
+    path.len() <= key.len() * 8 && path.iter().enumerate().all(|(i, &b)| bit(key, i) == b)
+}
+
+/// Node being rebuilt by `rebuild_subtree()`: the keys in `lo..hi` are under it,
+/// parting at `depth` bits where the keys in `mid..hi` go to the right
+struct Rebuilding {
+    depth: usize,
+    lo: usize,
+    mid: usize,
+    hi: usize,
+    hashes: Vec<Hash>,
+}
+
+impl Rebuilding {
+    /// Find where the keys sorted part, both sides having some of them
+    fn new(keys: &[(Vec<u8>, Hash)], depth: usize, lo: usize, hi: usize) -> Option<Self> {
+        let mid = lo + keys[lo..hi].iter().position(|(key, _)| bit(key, depth))?;
+        match mid > lo {
+            true => Some(Rebuilding {
+                depth,
+                lo,
+                mid,
+                hi,
+                hashes: Vec::new(),
+            }),
+            false => None,
         }
     }
+
+    /// The bits of the unit over the keys in `lo..hi`, from the depth of the node,
+    /// with the bits common to all of them, or the rest of the key if it is alone
+    fn bits<'a>(keys: &'a [(Vec<u8>, Hash)], depth: usize, lo: usize, hi: usize) -> Bits<'a> {
+        let first = Bits::new(&keys[lo].0).shift(depth as BitsLen, false);
+        match hi - lo {
+            1 => first,
+            _ => {
+                let last = Bits::new(&keys[hi - 1].0).shift(depth as BitsLen, false);
+                first.shift(Bits::len_common_bits(&first, &last), true)
+            }
+        }
+    }
+}
+
+/// Rebuild the hash of the node under a unit from all the keys under it, sorted and encoded.
+/// As the tree has a single shape for a given set of keys, the node is `Node::Hard`
+/// where the keys part right under the unit, and so are the nodes below it.
+/// The nodes are rebuilt with a stack of their own as `fold_partial_tree()` does.
+fn rebuild_subtree<H: Hasher>(hasher: &H, keys: &[(Vec<u8>, Hash)], depth: usize) -> Option<Hash> {
+    let mut stack = vec![Rebuilding::new(keys, depth, 0, keys.len())?];
+    while let Some(node) = stack.last_mut() {
+        let (lo, hi) = match node.hashes.len() {
+            0 => (node.lo, node.mid),
+            1 => (node.mid, node.hi),
+            _ => {
+                let node = stack.pop()?;
+                let unit = |hash, lo, hi| {
+                    let bits = Rebuilding::bits(keys, node.depth, lo, hi);
+                    Some(Unit { hash, bits })
+                };
+                let lc = unit(&node.hashes[0], node.lo, node.mid);
+                let rc = unit(&node.hashes[1], node.mid, node.hi);
+                let hash = hasher.digest(&Node::new(lc, rc).to_bytes().ok()?);
+                match stack.last_mut() {
+                    None => return Some(hash),
+                    Some(parent) => parent.hashes.push(hash),
+                }
+                continue;
+            }
+        };
+        if hi - lo == 1 {
+            node.hashes.push(keys[lo].1);
+        } else {
+            let depth = node.depth + Rebuilding::bits(keys, node.depth, lo, hi).len() as usize;
+            let next = Rebuilding::new(keys, depth, lo, hi)?;
+            stack.push(next);
+        }
+    }
+    None
+}
+
+/// Node being folded by `fold_partial_tree()`, with the units still to take its hash
+struct Folding {
+    bytes: Vec<u8>,
+    depth: usize,
+    units: Vec<(usize, Vec<bool>)>,
+    next: usize,
+}
+
+impl Folding {
+    /// Parse a node of a proof, `depth` bits down the tree
+    fn new(bytes: &[u8], depth: usize) -> Option<Self> {
+        let (lc, rc) = Node::cells_from_bytes(bytes, false).ok()?;
+        let offsets = [0, bytes.len() - HASH_LEN - 1];
+        let units = [lc, rc]
+            .iter()
+            .zip(offsets.iter())
+            .filter_map(|(cell, &i)| cell.as_ref().map(|unit| (i, unit.bits.to_bits())))
+            .collect();
+        Some(Folding {
+            bytes: bytes.to_vec(),
+            depth,
+            units,
+            next: 0,
+        })
+    }
+}
+
+/// Rebuild the hash of a node from a proof generated by `gen_partial_tree()`.
+/// The hashes of the units taken by `Take::Descend` come from the nodes following in the proof,
+/// and those taken by `Take::Rebuild` from `rebuild`.
+/// The nodes are folded with a stack of their own and a single path shared by all,
+/// so that neither the call stack nor the memory grows with how deep the proof goes.
+fn fold_partial_tree<H: Hasher>(
+    hasher: &H,
+    take: &dyn Fn(&[bool]) -> Take,
+    nodes: &mut std::slice::Iter<Vec<u8>>,
+    rebuild: &mut dyn FnMut(&[bool]) -> Option<Hash>,
+) -> Option<Hash> {
+    let mut path: Vec<bool> = Vec::new();
+    let mut stack = vec![Folding::new(nodes.next()?, 0)?];
+    while let Some(node) = stack.last_mut() {
+        if node.next == node.units.len() {
+            let hash = hasher.digest(&node.bytes);
+            stack.pop();
+            match stack.last_mut() {
+                None => return Some(hash),
+                Some(parent) => {
+                    let i = parent.units[parent.next].0;
+                    parent.bytes[i..i + HASH_LEN].copy_from_slice(&hash);
+                    parent.next += 1;
+                }
+            }
+            continue;
+        }
+        let i = node.units[node.next].0;
+        path.truncate(node.depth);
+        path.extend_from_slice(&node.units[node.next].1);
+        if path.len() > BitsLen::max_value() as usize {
+            return None;
+        }
+        match take(&path) {
+            Take::Hash => node.next += 1,
+            Take::Rebuild => {
+                node.bytes[i..i + HASH_LEN].copy_from_slice(&rebuild(&path)?);
+                node.next += 1;
+            }
+            Take::Descend => {
+                let next = Folding::new(nodes.next()?, path.len())?;
+                stack.push(next);
+            }
+        }
+    }
+    None
+}
+
+/// Fold the cuts of a Merkle proof from the bottom up, starting off with the given hash
+/// Returns `None` if any of the cuts is not a part of a node.
+fn fold_proof<H: Hasher>(hasher: &H, hash: &Hash, proof: &Proof) -> Option<Hash> {
+    proof.iter().rev().try_fold(*hash, |hash, (right, cut)| {
+        if !is_valid_cut(*right, cut) {
+            return None;
+        }
+        if *right {
+            let l = cut.len();
+            let o = [&cut[..l - 1], &hash[..], &cut[l - 1..]].concat();
+            Some(hasher.digest(&o))
+        } else {
+            let o = [&hash[..], &cut[..]].concat();
+            Some(hasher.digest(&o))
+        }
+    })
+}
+
+/// Get the bits of the unit on the path out of a cut in a Merkle proof
+/// - `Node::Soft` or left of `Node::Hard`: `[bits][..]`
+/// - right of `Node::Hard`: `[hash][left bits][bits][0x01]`
+fn bits_from_cut(right: bool, cut: &[u8]) -> Bits<'_> {
+    let i = if right { HASH_LEN } else { 0 };
+    let bits = Bits::from_bytes(&cut[i..]);
+    let n = 4 + nbytes_across(bits.range.start, bits.range.end) as usize;
+    if right {
+        Bits::from_bytes(&cut[i + n..cut.len() - 1])
+    } else {
+        Bits::from_bytes(&cut[i..i + n])
+    }
+}
+
+/// Follow the key along the cuts of a Merkle proof.
+/// Returns the number of key bits consumed by the path,
+/// or `None` if the path goes off the key somewhere.
+fn len_path_on_key(proof: &Proof, key: &[u8]) -> Option<BitsLen> {
+    let key = Bits::new(key);
+    proof.iter().try_fold(0, |n, (right, cut)| {
+        if !is_valid_cut(*right, cut) {
+            return None;
+        }
+        let bits = bits_from_cut(*right, cut);
+        let range = key.range.start + n..key.range.end;
+        match len_lcp(bits.path, &bits.range, key.path, &range) {
+            m if m == bits.len() => Some(n + m),
+            _ => None,
+        }
+    })
 }
@@ -144,8 +144,8 @@ where
 /// Get ith-index-bit from bytes
 /// Note that index i starts from 0
 pub fn bit<T: PrimInt + NumCast>(bytes: &[u8], i: T) -> bool {
-    let q = i.to_usize().expect("bit(): usize") / 8;
-    let r = i.to_u8().expect("bit(): u8") % 8;
+    let i = i.to_usize().expect("bit(): usize");
+    let (q, r) = (i / 8, i % 8);
     (bytes[q] >> (7 - r)) & 0x01 == 0x01
 }
 
@@ -223,6 +223,63 @@ pub fn bits_to_bytes(bits: &[bool]) -> Vec<u8> {
         .collect()
 }
 
+/// Encode a key of arbitrary length into a prefix-free form, which the tree works with.
+/// Every 0x00 byte of the key is escaped into 0x00 0xff, then 0x00 0x01 terminates the key.
+/// Since 0x00 0x01 never shows up inside an encoded key, no encoded key can be
+/// a prefix of another, yet the lexicographic order of keys is preserved.
+/// The encoded key must fit in the range of `BitsLen`, limiting the length of keys.
+pub fn encode_key(key: &[u8]) -> Result<Vec<u8>> {
+    let mut bytes: Vec<u8> = Vec::with_capacity(key.len() + 2);
+    for &byte in key.iter() {
+        match byte {
+            0x00 => bytes.extend_from_slice(&[0x00, 0xff]),
+            _ => bytes.push(byte),
+        }
+    }
+    bytes.extend_from_slice(&[0x00, 0x01]);
+    match bytes.len() {
+        n if n * 8 > BitsLen::max_value() as usize => Err(Error::InvalidKeyLength(key.len())),
+        _ => Ok(bytes),
+    }
+}
+
+/// Decode a key encoded by `encode_key()` back into the original key
+pub fn decode_key(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut key: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1)) {
+            (0x00, Some(&0xff)) => {
+                key.push(0x00);
+                i += 2;
+            }
+            (0x00, Some(&0x01)) if i + 2 == bytes.len() => return Some(key),
+            (0x00, _) => return None,
+            (byte, _) => {
+                key.push(byte);
+                i += 1;
+            }
+        }
+    }
+    None
+}
+
+/// Tell if the bits on a path make up a whole encoded key, ending with the terminator.
+/// Any path going through the tree which does not is a proper prefix of keys.
+pub fn is_complete_key(bits: &[bool]) -> bool {
+    let l = bits.len();
+    l >= 16 && l % 8 == 0 && bits_to_bytes(&bits[l - 16..]) == [0x00, 0x01]
+}
+
+/// Extend a path with the bits of a unit on the way down to a key.
+/// A path longer than any encoded key can only come out of a corrupt node.
+pub fn join_path(path: &[bool], bits: &Bits) -> Result<Vec<bool>> {
+    match path.len() + bits.len() as usize {
+        n if n > BitsLen::max_value() as usize => Err(Error::CorruptNode),
+        _ => Ok([path, &bits.to_bits()].concat()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -232,6 +289,8 @@ mod tests {
         assert_eq!(bit(&bytes, 10), true);
         assert_eq!(bit(&bytes, 20), false);
         assert_eq!(bit(&bytes, 30), false);
+        let bytes = [&[0x00; 40][..], &[0x01]].concat();
+        assert_eq!(bit(&bytes, 327u16), true);
     }
 
     #[test]
@@ -307,4 +366,43 @@ mod tests {
         assert_eq!(len_lcp(&sofia, &(2..9), &maria, &(18..30)), 5);
         assert_eq!(len_lcp(&sofia, &(20..30), &maria, &(3..15)), 4);
     }
+
+    #[test]
+    fn test_encode_key() {
+        assert_eq!(encode_key(&[]).unwrap(), [0x00, 0x01]);
+        assert_eq!(encode_key(&[0x61]).unwrap(), [0x61, 0x00, 0x01]);
+        assert_eq!(
+            encode_key(&[0x00, 0x61, 0x00]).unwrap(),
+            [0x00, 0xff, 0x61, 0x00, 0xff, 0x00, 0x01]
+        );
+        assert!(matches!(
+            encode_key(&[0x61; 8190]),
+            Err(Error::InvalidKeyLength(8190))
+        ));
+
+        // the order of keys preserved
+        assert!(encode_key(&[0x61]).unwrap() < encode_key(&[0x61, 0x00]).unwrap());
+        assert!(encode_key(&[0x61, 0x00]).unwrap() < encode_key(&[0x61, 0x01]).unwrap());
+    }
+
+    #[test]
+    fn test_decode_key() {
+        let key = [0x00, 0x61, 0x00, 0x00, 0xff, 0x01];
+        assert_eq!(decode_key(&encode_key(&key).unwrap()).unwrap(), key);
+        assert_eq!(decode_key(&encode_key(&[]).unwrap()).unwrap(), []);
+        assert_eq!(decode_key(&[0x61, 0x00]), None);
+        assert_eq!(decode_key(&[0x61, 0x00, 0x01, 0x61]), None);
+        assert_eq!(decode_key(&[0x00, 0x02]), None);
+    }
+
+    #[test]
+    fn test_is_complete_key() {
+        let key = encode_key(&[0x00, 0x61]).unwrap();
+        assert_eq!(is_complete_key(&bytes_to_bits(&key)), true);
+        assert_eq!(
+            is_complete_key(&bytes_to_bits(&key[..key.len() - 1])),
+            false
+        );
+        assert_eq!(is_complete_key(&bytes_to_bits(&key[..2])), false);
+    }
 }
@@ -0,0 +1,354 @@
+use monotree::database::MemoryDB;
+use monotree::hasher::Blake3;
+use monotree::utils::{encode_key, nbytes_across};
+use monotree::{
+    tree, Database, Error, Hash, Hasher, IterableDatabase, Keys, Monotree, Node, NonInclusionProof,
+    Proof, Result,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::{Cell, RefCell};
+use std::{env, thread};
+
+const ROUNDS: usize = 1000;
+
+thread_local! {
+    /// Seed of the test running on the thread, set by `seeded()`
+    static SEED: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Random generator of a test, seeded with `MONOTREE_FUZZ_SEED` if set, or at random.
+/// The seed is printed when the test fails, so that it can be run again with it.
+/// The databases of the test are seeded from it as well, through `rng_of_db()`.
+fn seeded() -> (impl Drop, StdRng) {
+    let seed = match env::var("MONOTREE_FUZZ_SEED") {
+        Ok(seed) => seed.parse().expect("MONOTREE_FUZZ_SEED: not a u64"),
+        Err(_) => rand::thread_rng().gen(),
+    };
+    SEED.with(|cell| cell.set(seed));
+    let guard = scopeguard::guard(seed, |seed| {
+        if thread::panicking() {
+            eprintln!("failed with MONOTREE_FUZZ_SEED={}", seed);
+        }
+    });
+    (guard, StdRng::seed_from_u64(seed))
+}
+
+/// Random generator of a database, apart from that of the test but given by the same seed
+fn rng_of_db() -> RefCell<StdRng> {
+    let seed = SEED.with(|cell| cell.get());
+    RefCell::new(StdRng::seed_from_u64(!seed))
+}
+
+fn random_bytes(rng: &mut StdRng, n: usize) -> Vec<u8> {
+    (0..n).map(|_| rng.gen()).collect()
+}
+
+fn random_hashes(rng: &mut StdRng, n: usize) -> Vec<Hash> {
+    (0..n).map(|_| rng.gen()).collect()
+}
+
+/// Bytes that look like a node: a valid layout at times, and broken in a random way otherwise
+fn random_node(rng: &mut StdRng) -> Vec<u8> {
+    let mut unit = || {
+        let start: u16 = rng.gen_range(0, 8);
+        let end: u16 = start + rng.gen_range(1, 64);
+        let n = nbytes_across(start, end) as usize;
+        let bits = [
+            &start.to_be_bytes()[..],
+            &end.to_be_bytes(),
+            &random_bytes(rng, n),
+        ]
+        .concat();
+        (rng.gen::<Hash>(), bits)
+    };
+    let (lu, ru) = (unit(), unit());
+    let mut bytes = match rng.gen_range(0, 2) {
+        0 => [&lu.0[..], &lu.1, &[0x00]].concat(),
+        _ => [&lu.0[..], &lu.1, &ru.1, &ru.0, &[0x01]].concat(),
+    };
+    match rng.gen_range(0, 4) {
+        0 => {
+            let i = rng.gen_range(0, bytes.len());
+            bytes[i] = rng.gen();
+        }
+        1 => bytes.truncate(rng.gen_range(0, bytes.len())),
+        2 => {
+            let n = rng.gen_range(0, 128);
+            bytes = random_bytes(rng, n);
+        }
+        _ => {}
+    }
+    bytes
+}
+
+/// Database giving garbage for any key: nothing at times, or bytes from `random_node()`
+struct GarbageDB(RefCell<StdRng>);
+
+impl Database for GarbageDB {
+    fn new(_dbpath: &str) -> Self {
+        GarbageDB(rng_of_db())
+    }
+
+    fn get(&self, _key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let rng = &mut *self.0.borrow_mut();
+        match rng.gen_range(0, 8) {
+            0 => Ok(None),
+            _ => Ok(Some(random_node(rng))),
+        }
+    }
+
+    fn put(&mut self, _key: &[u8], _value: Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete(&mut self, _key: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn init_batch(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn finish_batch(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl IterableDatabase for GarbageDB {
+    fn keys(&self) -> Result<Keys<'_>> {
+        let keys = random_hashes(&mut self.0.borrow_mut(), 10);
+        Ok(Box::new(keys.into_iter().map(Ok)))
+    }
+}
+
+/// Database flipping a random byte of what it reads at times, as disk corruption does
+struct CorruptDB(MemoryDB, RefCell<StdRng>);
+
+impl Database for CorruptDB {
+    fn new(dbpath: &str) -> Self {
+        CorruptDB(MemoryDB::new(dbpath), rng_of_db())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let rng = &mut *self.1.borrow_mut();
+        match self.0.get(key)? {
+            Some(mut bytes) if !bytes.is_empty() && rng.gen_range(0, 4) == 0 => {
+                let i = rng.gen_range(0, bytes.len());
+                bytes[i] ^= 1 << rng.gen_range(0, 8);
+                Ok(Some(bytes))
+            }
+            bytes => Ok(bytes),
+        }
+    }
+
+    fn put(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.0.put(key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.0.delete(key)
+    }
+
+    fn init_batch(&mut self) -> Result<()> {
+        self.0.init_batch()
+    }
+
+    fn finish_batch(&mut self) -> Result<()> {
+        self.0.finish_batch()
+    }
+}
+
+impl IterableDatabase for CorruptDB {
+    fn keys(&self) -> Result<Keys<'_>> {
+        self.0.keys()
+    }
+}
+
+/// Call every public method of the tree with the root. Errors are fine, but panics are not.
+fn call_all<D: IterableDatabase>(
+    rng: &mut StdRng,
+    tree: &mut Monotree<D>,
+    hash: &Hash,
+    keys: &[Hash],
+) {
+    let root = Some(hash);
+    let (key, leaf) = (&keys[0], &keys[1]);
+    let _ = tree.insert(root, key, leaf);
+    let _ = tree.get(root, key);
+    let _ = tree.remove(root, key);
+    let _ = tree.inserts(root, keys, keys);
+    let _ = tree.gets(root, keys);
+    let _ = tree.removes(root, keys);
+    let _ = tree.insert_value(root, key, &random_bytes(rng, 100));
+    let _ = tree.get_value(root, key);
+    let _ = tree.get_merkle_proof(root, key);
+    let _ = tree.get_non_inclusion_proof(root, key);
+    let _ = tree.get_range_proof(root, &keys[0], &keys[1]);
+    let _ = tree.get_multi_proof(root, keys);
+    let _ = tree.iter(root).take(100).count();
+    if let Ok(iter) = tree.range(root, keys[0]..keys[1]) {
+        let _ = iter.take(100).count();
+    }
+    if let Ok(iter) = tree.prefix(root, &keys[0][..1]) {
+        let _ = iter.take(100).count();
+    }
+    let _ = tree.prune(&[*hash]);
+}
+
+fn is_missing<T>(result: Result<T>, root: &Hash) -> bool {
+    matches!(result, Err(Error::MissingNode(hash)) if &hash == root)
+}
+
+#[test]
+fn test_fuzz_random_roots() -> Result<()> {
+    let (_seed, mut rng) = seeded();
+    let mut tree = Monotree::default();
+    let keys = random_hashes(&mut rng, 100);
+    tree.inserts(None, &keys, &keys)?;
+    for _ in 0..ROUNDS {
+        let root: Hash = rng.gen();
+        assert!(is_missing(tree.get(Some(&root), &keys[0]), &root));
+        assert!(is_missing(
+            tree.insert(Some(&root), &keys[0], &keys[0]),
+            &root
+        ));
+        assert!(is_missing(tree.remove(Some(&root), &keys[0]), &root));
+        assert!(is_missing(
+            tree.get_merkle_proof(Some(&root), &keys[0]),
+            &root
+        ));
+        assert!(is_missing(
+            tree.get_non_inclusion_proof(Some(&root), &keys[0]),
+            &root
+        ));
+        assert!(is_missing(
+            tree.get_range_proof(Some(&root), &keys[0], &keys[1]),
+            &root
+        ));
+        assert!(is_missing(tree.get_multi_proof(Some(&root), &keys), &root));
+        assert!(is_missing(tree.iter(Some(&root)).next().unwrap(), &root));
+        assert!(is_missing(tree.prune(&[root]), &root));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_fuzz_garbage_nodes() {
+    let (_seed, mut rng) = seeded();
+    let mut tree = Monotree::<GarbageDB>::new("garbage");
+    for _ in 0..ROUNDS {
+        let (root, keys) = (rng.gen(), random_hashes(&mut rng, 10));
+        call_all(&mut rng, &mut tree, &root, &keys);
+    }
+}
+
+#[test]
+fn test_fuzz_corrupt_nodes() -> Result<()> {
+    let (_seed, mut rng) = seeded();
+    let mut tree = Monotree::<CorruptDB>::new("corrupt");
+    let keys = random_hashes(&mut rng, 100);
+    let mut roots = Vec::new();
+    let mut root = None;
+    for key in keys.iter() {
+        if let Ok(Some(hash)) = tree.insert(root.as_ref(), key, key) {
+            root = Some(hash);
+            roots.push(hash);
+        }
+    }
+    for _ in 0..ROUNDS {
+        let root = &roots[rng.gen_range(0, roots.len())];
+        call_all(&mut rng, &mut tree, root, &keys[..10]);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_fuzz_random_proofs() -> Result<()> {
+    let (_seed, mut rng) = seeded();
+    let mut tree = Monotree::default();
+    let keys = random_hashes(&mut rng, 100);
+    let root = tree.inserts(None, &keys, &keys)?;
+    let hasher = Blake3::new();
+    let cuts = tree.get_merkle_proof(root.as_ref(), &keys[0])?.unwrap();
+    for _ in 0..ROUNDS {
+        // valid cuts with a random byte, or random cuts
+        let mut proof = cuts.clone();
+        let i = rng.gen_range(0, proof.len());
+        match proof[i].1.len() {
+            0 => {}
+            n => proof[i].1[rng.gen_range(0, n)] = rng.gen(),
+        }
+        let node = random_node(&mut rng);
+        proof.push((rng.gen(), node));
+        let n = rng.gen_range(0, 64);
+        proof.push((rng.gen(), random_bytes(&mut rng, n)));
+        let proof = Proof::from(proof[rng.gen_range(0, 3)..].to_vec());
+
+        let (root, key) = (root.as_ref(), &keys[0]);
+        assert!(!tree::verify_proof(&hasher, root, key, Some(&proof)));
+        assert!(!tree::verify_proof_for_key(
+            &hasher,
+            root,
+            key,
+            key,
+            Some(&proof)
+        ));
+        let node = random_node(&mut rng);
+        let absence = NonInclusionProof { path: proof, node };
+        let _ = tree::verify_non_inclusion(&hasher, root, key, Some(&absence));
+        let n = rng.gen_range(0, 8);
+        let nodes: Vec<Vec<u8>> = (0..n).map(|_| random_node(&mut rng)).collect();
+        let entries = [(key.to_vec(), *key)];
+        let _ = tree::verify_range_proof(&hasher, root, &keys[0], &keys[1], &entries, &nodes);
+        let entries = [(*key, *key)];
+        let _ = tree::verify_multi_proof(&hasher, root, &entries, Some(&nodes));
+
+        let _ = Node::from_bytes(&random_node(&mut rng));
+        let n = rng.gen_range(0, 128);
+        let _ = Proof::decode(&random_bytes(&mut rng, n));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_fuzz_deep_proofs() {
+    let (_seed, mut rng) = seeded();
+    // a chain of nodes consuming a single bit each, down to the leaf of a long key
+    let key = vec![0x01; 2000];
+    let bits = encode_key(&key).unwrap();
+    let len = bits.len() as u16 * 8;
+    let unit = |start: u16, end: u16| {
+        let bytes = &bits[start as usize / 8..nbytes_across(0, end) as usize];
+        let (start, end) = (start % 8, end - start / 8 * 8);
+        [
+            &[0x00; 32][..],
+            &start.to_be_bytes(),
+            &end.to_be_bytes(),
+            bytes,
+            &[0x00],
+        ]
+        .concat()
+    };
+    let mut nodes: Vec<Vec<u8>> = (0..len - 1).map(|i| unit(i, i + 1)).collect();
+    nodes.push(unit(len - 1, len));
+
+    let hasher = Blake3::new();
+    let root: Hash = rng.gen();
+    let entries = [(key.clone(), rng.gen())];
+    assert!(!tree::verify_multi_proof(
+        &hasher,
+        Some(&root),
+        &entries,
+        Some(&nodes)
+    ));
+    let end = [&key[..], &[0x02]].concat();
+    assert!(!tree::verify_range_proof(
+        &hasher,
+        Some(&root),
+        &key,
+        &end,
+        &entries,
+        &nodes
+    ));
+}
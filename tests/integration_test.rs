@@ -1,12 +1,31 @@
-use monotree::database::{MemoryDB, RocksDB, Sled};
+use monotree::database::{
+    BatchingDb, CachedDb, CachedFlatFile, FlatFile, MemoryDB, RawRocksDB, RawSled, RefCounted,
+    RocksDB, Sled,
+};
+#[cfg(feature = "lmdb")]
+use monotree::database::{CachedLmdb, Lmdb};
+#[cfg(feature = "rusqlite")]
+use monotree::database::{CachedSqlite, Sqlite};
 use monotree::hasher::{Blake2b, Blake2s, Blake3, Sha2, Sha3};
-use monotree::utils::{random_bytes, random_hashes, shuffle};
-use monotree::{hex, tree, Database, Hash, Hasher, Monotree, Result};
+use monotree::utils::{get_sorted_indices, random_bytes, random_hashes, shuffle};
+use monotree::{
+    hex, tree, Database, Error, Hash, Hasher, IterableDatabase, Monotree, Op, Proof, Result,
+    SharedDatabase, SharedMonotree, HASH_LEN,
+};
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::Write;
+use std::sync::{mpsc, Arc};
+use std::thread;
 
 extern crate paste;
 extern crate scopeguard;
 
+type RefCountedMemoryDB = RefCounted<MemoryDB>;
+type RefCountedRocksDB = RefCounted<RawRocksDB>;
+type RefCountedSled = RefCounted<RawSled>;
+type RefCountedFlatFile = RefCounted<FlatFile>;
+
 fn insert_keys_then_verify_values<D: Database, H: Hasher>(
     mut tree: Monotree<D, H>,
     _hasher: &H,
@@ -45,12 +64,64 @@ fn insert_keys_then_gen_and_verify_proof<D: Database, H: Hasher>(
                 tree::verify_proof(hasher, root.as_ref(), v, proof.as_ref()),
                 true
             );
+            assert_eq!(
+                tree::verify_proof_for_key(hasher, root.as_ref(), k, v, proof.as_ref()),
+                true
+            );
+        }
+        // the proof must not be valid for other keys
+        if i > 0 {
+            let proof = tree.get_merkle_proof(root.as_ref(), key)?;
+            assert_eq!(
+                tree::verify_proof_for_key(hasher, root.as_ref(), &keys[0], value, proof.as_ref()),
+                false
+            );
         }
     }
     assert_ne!(root, None);
     Ok(())
 }
 
+fn insert_keys_then_gen_and_verify_non_inclusion_proof<D: Database, H: Hasher>(
+    mut tree: Monotree<D, H>,
+    hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()> {
+    // the empty tree includes no key at all
+    let proof = tree.get_non_inclusion_proof(root.as_ref(), &keys[0])?;
+    assert_eq!(
+        tree::verify_non_inclusion(hasher, root.as_ref(), &keys[0], proof.as_ref()),
+        true
+    );
+
+    // insert a half of keys, then keep the rest out of tree
+    let (keys, absents) = keys.split_at(keys.len() / 2);
+    root = tree.inserts(root.as_ref(), keys, &leaves[..keys.len()])?;
+
+    // no non-inclusion proof for the keys in the tree
+    for key in keys.iter() {
+        assert_eq!(tree.get_non_inclusion_proof(root.as_ref(), key)?, None);
+    }
+
+    // generate and verify non-inclusion proof with the keys out of tree
+    for key in absents.iter() {
+        let proof = tree.get_non_inclusion_proof(root.as_ref(), key)?;
+        assert_ne!(proof, None);
+        assert_eq!(
+            tree::verify_non_inclusion(hasher, root.as_ref(), key, proof.as_ref()),
+            true
+        );
+        // the proof must not be valid for the keys in the tree
+        assert_eq!(
+            tree::verify_non_inclusion(hasher, root.as_ref(), &keys[0], proof.as_ref()),
+            false
+        );
+    }
+    Ok(())
+}
+
 fn insert_keys_then_delete_keys_in_order<D: Database, H: Hasher>(
     mut tree: Monotree<D, H>,
     hasher: &H,
@@ -175,6 +246,989 @@ fn insert_keys_then_delete_keys_immediately<D: Database, H: Hasher>(
     Ok(())
 }
 
+fn insert_keys_then_prune_unreachable_nodes<D: IterableDatabase, H: Hasher>(
+    mut tree: Monotree<D, H>,
+    hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()> {
+    // every single insertion leaves garbage nodes behind
+    for (key, value) in keys.iter().zip(leaves.iter()) {
+        root = tree.insert(root.as_ref(), key, value)?;
+    }
+    // remove a half of keys, yielding another root
+    let (removed, kept) = keys.split_at(keys.len() / 2);
+    let old = root;
+    root = tree.removes(root.as_ref(), removed)?;
+
+    // keep both roots: nothing reachable from them must be gone
+    assert_ne!(tree.prune(&[old.unwrap(), root.unwrap()])?, 0);
+    for (key, value) in keys.iter().zip(leaves.iter()) {
+        assert_eq!(tree.get(old.as_ref(), key)?, Some(*value));
+    }
+
+    // keep the last root only: pruning again must be idempotent
+    assert_ne!(tree.prune(&[root.unwrap()])?, 0);
+    assert_eq!(tree.prune(&[root.unwrap()])?, 0);
+    for (key, value) in kept.iter().zip(leaves.iter().skip(removed.len())) {
+        assert_eq!(tree.get(root.as_ref(), key)?, Some(*value));
+        let proof = tree.get_merkle_proof(root.as_ref(), key)?;
+        assert_eq!(
+            tree::verify_proof(hasher, root.as_ref(), value, proof.as_ref()),
+            true
+        );
+    }
+    for key in removed.iter() {
+        assert_eq!(tree.get(root.as_ref(), key)?, None);
+    }
+    Ok(())
+}
+
+fn insert_values_then_get_values_and_verify_proof<D: IterableDatabase, H: Hasher>(
+    mut tree: Monotree<D, H>,
+    hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    _leaves: &[Hash],
+) -> Result<()> {
+    // values of arbitrary length, instead of leaves
+    let values: Vec<Vec<u8>> = (0..keys.len())
+        .map(|i| random_bytes(100 + i % 400))
+        .collect();
+    for (key, value) in keys.iter().zip(values.iter()) {
+        root = tree.insert_value(root.as_ref(), key, value)?;
+    }
+    // remove a half of keys, then prune: the values left must be intact
+    let (removed, kept) = keys.split_at(keys.len() / 2);
+    root = tree.removes(root.as_ref(), removed)?;
+    tree.prune(&[root.unwrap()])?;
+    for (key, value) in kept.iter().zip(values.iter().skip(removed.len())) {
+        assert_eq!(tree.get_value(root.as_ref(), key)?.as_ref(), Some(value));
+
+        // proofs go with the hash of value
+        let leaf = hasher.digest(value);
+        let proof = tree.get_merkle_proof(root.as_ref(), key)?;
+        assert_eq!(
+            tree::verify_proof(hasher, root.as_ref(), &leaf, proof.as_ref()),
+            true
+        );
+    }
+    for key in removed.iter() {
+        assert_eq!(tree.get_value(root.as_ref(), key)?, None);
+    }
+    Ok(())
+}
+
+fn insert_variable_length_keys_then_delete_keys<D: Database, H: Hasher>(
+    mut tree: Monotree<D, H>,
+    hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()> {
+    // keys of arbitrary length, a lot of which are prefixes of the others:
+    // including empty keys and trailing zeros
+    let mut entries: BTreeMap<Vec<u8>, Hash> = BTreeMap::new();
+    for (key, leaf) in keys.iter().zip(leaves.iter()) {
+        let n = key[0] as usize % (HASH_LEN + 1);
+        entries.insert(key[..n].to_vec(), *leaf);
+        entries.insert([&key[..n / 2], &[0x00]].concat(), *leaf);
+    }
+    let (keys, leaves): (Vec<Vec<u8>>, Vec<Hash>) = entries.into_iter().unzip();
+    root = tree.inserts(root.as_ref(), &keys, &leaves)?;
+    for (key, leaf) in keys.iter().zip(leaves.iter()) {
+        assert_eq!(tree.get(root.as_ref(), key)?, Some(*leaf));
+        let proof = tree.get_merkle_proof(root.as_ref(), key)?;
+        assert_eq!(
+            tree::verify_proof_for_key(hasher, root.as_ref(), key, leaf, proof.as_ref()),
+            true
+        );
+    }
+    // delete keys one by one, from the longest
+    for (i, key) in keys.iter().enumerate().rev() {
+        root = tree.remove(root.as_ref(), key)?;
+        assert_eq!(tree.get(root.as_ref(), key)?, None);
+        for (k, v) in keys.iter().zip(leaves.iter()).take(i) {
+            assert_eq!(tree.get(root.as_ref(), k)?, Some(*v));
+        }
+    }
+    assert_eq!(root, None);
+    Ok(())
+}
+
+fn insert_keys_then_iterate_in_order<D: Database, H: Hasher>(
+    mut tree: Monotree<D, H>,
+    _hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()> {
+    assert_eq!(tree.iter(root.as_ref()).count(), 0);
+
+    // keys with their prefixes, which must come out before the keys
+    let mut entries: BTreeMap<Vec<u8>, Hash> = BTreeMap::new();
+    for (key, leaf) in keys.iter().zip(leaves.iter()) {
+        entries.insert(key.to_vec(), *leaf);
+        entries.insert(key[..key[0] as usize % HASH_LEN].to_vec(), *leaf);
+    }
+    for (key, leaf) in entries.iter() {
+        root = tree.insert(root.as_ref(), key, leaf)?;
+    }
+    let found = tree.iter(root.as_ref()).collect::<Result<Vec<_>>>()?;
+    assert_eq!(found, entries.into_iter().collect::<Vec<_>>());
+
+    // the iterator is lazy
+    let first = tree.iter(root.as_ref()).next().unwrap()?;
+    assert_eq!(first, found[0]);
+    Ok(())
+}
+
+fn insert_keys_then_query_range_and_prefix<D: Database, H: Hasher>(
+    mut tree: Monotree<D, H>,
+    _hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()> {
+    // keys with their prefixes, then query with them as bounds
+    let mut entries: BTreeMap<Vec<u8>, Hash> = BTreeMap::new();
+    for (key, leaf) in keys.iter().zip(leaves.iter()) {
+        entries.insert(key.to_vec(), *leaf);
+        entries.insert(key[..key[0] as usize % 4].to_vec(), *leaf);
+    }
+    for (key, leaf) in entries.iter() {
+        root = tree.insert(root.as_ref(), key, leaf)?;
+    }
+    let sorted: Vec<Vec<u8>> = entries.keys().cloned().collect();
+    let (a, b) = (&sorted[sorted.len() / 4][..], &sorted[sorted.len() / 2][..]);
+    let found = tree
+        .range(root.as_ref(), a..b)?
+        .collect::<Result<Vec<_>>>()?;
+    let expected: Vec<_> = entries
+        .range(a.to_vec()..b.to_vec())
+        .map(|(k, v)| (k.clone(), *v))
+        .collect();
+    assert_eq!(found, expected);
+
+    let found = tree
+        .range(root.as_ref(), a..=b)?
+        .collect::<Result<Vec<_>>>()?;
+    let expected: Vec<_> = entries
+        .range(a.to_vec()..=b.to_vec())
+        .map(|(k, v)| (k.clone(), *v))
+        .collect();
+    assert_eq!(found, expected);
+
+    let found = tree.range(root.as_ref(), ..b)?.count();
+    assert_eq!(found, sorted.len() / 2);
+    let found = tree.range(root.as_ref(), a..)?.count();
+    assert_eq!(found, sorted.len() - sorted.len() / 4);
+    assert_eq!(tree.range(root.as_ref(), b..a)?.count(), 0);
+
+    // prefixes of 0, 1 and 2 bytes, including the ones not in the tree
+    for prefix in [
+        &[][..],
+        &a[..a.len().min(1)],
+        &b[..b.len().min(2)],
+        &[0x00, 0x00],
+    ]
+    .iter()
+    {
+        let found = tree
+            .prefix(root.as_ref(), prefix)?
+            .collect::<Result<Vec<_>>>()?;
+        let expected: Vec<_> = entries
+            .iter()
+            .filter(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        assert_eq!(found, expected);
+    }
+    Ok(())
+}
+
+fn insert_keys_then_gen_and_verify_range_proof<D: Database, H: Hasher>(
+    mut tree: Monotree<D, H>,
+    hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()> {
+    // no entries in an empty tree
+    let (a, b) = (&keys[0][..], &keys[1][..]);
+    let proof = tree.get_range_proof(root.as_ref(), a, b)?;
+    assert_eq!(
+        tree::verify_range_proof(hasher, root.as_ref(), a, b, &[], &proof),
+        true
+    );
+
+    root = tree.inserts(root.as_ref(), keys, leaves)?;
+    let mut sorted = keys.to_vec();
+    sorted.sort();
+    let n = sorted.len();
+    for (a, b) in [
+        (n / 4, n / 2),
+        (0, n - 1),
+        (n / 2, n / 2 + 1),
+        (n / 2, n / 2),
+    ]
+    .iter()
+    {
+        let (a, b) = (&sorted[*a][..], &sorted[*b][..]);
+        let entries = tree
+            .range(root.as_ref(), a..b)?
+            .collect::<Result<Vec<_>>>()?;
+        let proof = tree.get_range_proof(root.as_ref(), a, b)?;
+        assert_eq!(
+            tree::verify_range_proof(hasher, root.as_ref(), a, b, &entries, &proof),
+            true
+        );
+        if entries.is_empty() {
+            continue;
+        }
+
+        // the subtrees within the range are rebuilt from the entries, not put in the proof
+        if entries.len() >= 64 {
+            assert!(proof.len() < entries.len() / 2);
+        }
+
+        // an entry left out or tampered must fail
+        let i = entries.len() / 2;
+        let omitted = [&entries[..i], &entries[i + 1..]].concat();
+        assert_eq!(
+            tree::verify_range_proof(hasher, root.as_ref(), a, b, &omitted, &proof),
+            false
+        );
+        let mut tampered = entries.clone();
+        tampered[i].1 = leaves[0];
+        tampered[i].1[0] ^= 0x01;
+        assert_eq!(
+            tree::verify_range_proof(hasher, root.as_ref(), a, b, &tampered, &proof),
+            false
+        );
+
+        // the proof must not be valid for another range
+        let c = &sorted[n - 1][..];
+        if c != b {
+            assert_eq!(
+                tree::verify_range_proof(hasher, root.as_ref(), a, c, &entries, &proof),
+                false
+            );
+        }
+    }
+    Ok(())
+}
+
+fn insert_keys_then_gen_and_verify_multi_proof<D: Database, H: Hasher>(
+    mut tree: Monotree<D, H>,
+    hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()> {
+    root = tree.inserts(root.as_ref(), keys, leaves)?;
+    let entries: Vec<(Hash, Hash)> = keys.iter().cloned().zip(leaves.iter().cloned()).collect();
+    for n in [1, keys.len() / 10, keys.len()].iter() {
+        let proof = tree.get_multi_proof(root.as_ref(), &keys[..*n])?;
+        assert_eq!(
+            tree::verify_multi_proof(hasher, root.as_ref(), &entries[..*n], proof.as_ref()),
+            true
+        );
+
+        // far smaller than the Merkle proofs of all the keys together
+        if *n == keys.len() {
+            let mut size = 0;
+            for key in keys.iter() {
+                let proof = tree.get_merkle_proof(root.as_ref(), key)?.unwrap();
+                size += proof.iter().map(|(_, cut)| cut.len()).sum::<usize>();
+            }
+            let proof = proof.as_ref().unwrap();
+            assert!(proof.iter().map(|node| node.len()).sum::<usize>() < size / 2);
+        }
+
+        // an entry left out, or a tampered leaf, must fail
+        if *n > 1 {
+            assert_eq!(
+                tree::verify_multi_proof(hasher, root.as_ref(), &entries[1..*n], proof.as_ref()),
+                false
+            );
+        }
+        let mut tampered = entries[..*n].to_vec();
+        tampered[0].1[0] ^= 0x01;
+        assert_eq!(
+            tree::verify_multi_proof(hasher, root.as_ref(), &tampered, proof.as_ref()),
+            false
+        );
+    }
+
+    // no proof for the keys if any of them is absent
+    root = tree.remove(root.as_ref(), &keys[0])?;
+    assert_eq!(tree.get_multi_proof(root.as_ref(), &keys[..10])?, None);
+    Ok(())
+}
+
+fn insert_keys_then_encode_and_decode_proof<D: Database, H: Hasher>(
+    mut tree: Monotree<D, H>,
+    hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()> {
+    root = tree.inserts(root.as_ref(), keys, leaves)?;
+    for (key, leaf) in keys.iter().zip(leaves.iter()) {
+        let proof = tree.get_merkle_proof(root.as_ref(), key)?.unwrap();
+        let bytes = proof.encode()?;
+        let decoded = Proof::decode(&bytes)?;
+        assert_eq!(decoded, proof);
+        assert_eq!(
+            tree::verify_proof_for_key(hasher, root.as_ref(), key, leaf, Some(&decoded)),
+            true
+        );
+    }
+
+    // malformed input must be rejected with an error
+    let proof = tree.get_merkle_proof(root.as_ref(), &keys[0])?.unwrap();
+    let bytes = proof.encode()?;
+    for i in 0..bytes.len() {
+        assert!(matches!(
+            Proof::decode(&bytes[..i]),
+            Err(Error::InvalidProof(_))
+        ));
+    }
+    assert!(matches!(
+        Proof::decode(&[&bytes[..], &[0x00]].concat()),
+        Err(Error::InvalidProof(_))
+    ));
+    assert!(matches!(
+        Proof::decode(&[&[0xff], &bytes[1..]].concat()),
+        Err(Error::InvalidProof(_))
+    ));
+    let mut side = bytes.clone();
+    side[3] = 0x02;
+    assert!(matches!(Proof::decode(&side), Err(Error::InvalidProof(_))));
+    let (right, cut) = &proof[0];
+    let mut tag = bytes.clone();
+    tag[6 + cut.len() - 1] = if *right { 0x00 } else { 0x02 };
+    assert!(matches!(Proof::decode(&tag), Err(Error::InvalidProof(_))));
+    Ok(())
+}
+
+fn insert_keys_then_read_from_threads<D, H>(
+    mut tree: Monotree<D, H>,
+    hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()>
+where
+    D: Database + Send + Sync + 'static,
+    H: Hasher + Send + Sync + 'static,
+{
+    root = tree.inserts(root.as_ref(), keys, leaves)?;
+
+    // readers share the tree, each reading and proving a part of keys
+    let shared = Arc::new(tree);
+    let handles: Vec<_> = keys
+        .chunks(keys.len() / 4)
+        .map(|keys| {
+            let (tree, keys) = (Arc::clone(&shared), keys.to_vec());
+            thread::spawn(move || -> Result<Vec<(Option<Hash>, Option<Proof>)>> {
+                keys.iter()
+                    .map(|key| {
+                        let leaf = tree.get(root.as_ref(), key)?;
+                        Ok((leaf, tree.get_merkle_proof(root.as_ref(), key)?))
+                    })
+                    .collect()
+            })
+        })
+        .collect();
+    let mut i = 0;
+    for handle in handles {
+        for (leaf, proof) in handle.join().unwrap()? {
+            assert_eq!(leaf, Some(leaves[i]));
+            assert!(tree::verify_proof(
+                hasher,
+                root.as_ref(),
+                &leaves[i],
+                proof.as_ref()
+            ));
+            i += 1;
+        }
+    }
+    assert_eq!(i, keys.len());
+    Ok(())
+}
+
+fn insert_keys_then_read_while_writing<D, H>(
+    tree: Monotree<D, H>,
+    hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()>
+where
+    D: SharedDatabase + Send + Sync + 'static,
+    H: Hasher + Send + Sync + 'static,
+{
+    let shared = Arc::new(SharedMonotree::from_tree(tree)?);
+
+    // the writer commits blocks of keys, while readers prove against each root committed
+    let (sender, receiver) = mpsc::channel();
+    let writer = {
+        let (shared, keys, leaves) = (Arc::clone(&shared), keys.to_vec(), leaves.to_vec());
+        thread::spawn(move || -> Result<()> {
+            for (i, (keys, leaves)) in keys.chunks(20).zip(leaves.chunks(20)).enumerate() {
+                root = shared.writer().inserts(root.as_ref(), keys, leaves)?;
+                sender.send((root, (i + 1) * 20)).unwrap();
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = receiver
+        .iter()
+        .map(|(root, n)| {
+            let (shared, keys) = (Arc::clone(&shared), keys[..n].to_vec());
+            let reader = thread::spawn(move || -> Result<Vec<(Option<Hash>, Option<Proof>)>> {
+                keys.iter()
+                    .map(|key| {
+                        let leaf = shared.get(root.as_ref(), key)?;
+                        Ok((leaf, shared.get_merkle_proof(root.as_ref(), key)?))
+                    })
+                    .collect()
+            });
+            (root, reader)
+        })
+        .collect();
+    writer.join().unwrap()?;
+    assert_eq!(readers.len(), keys.len() / 20);
+
+    for (root, reader) in readers {
+        for (i, (leaf, proof)) in reader.join().unwrap()?.into_iter().enumerate() {
+            assert_eq!(leaf, Some(leaves[i]));
+            assert!(tree::verify_proof(
+                hasher,
+                root.as_ref(),
+                &leaves[i],
+                proof.as_ref()
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn insert_keys_in_bulk_then_compare_roots<D: Database, H: Hasher>(
+    mut tree: Monotree<D, H>,
+    _hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()> {
+    // removals leave the tree as if the keys removed had never been inserted
+    let (old, new) = keys.split_at(keys.len() / 2);
+    let (gone, left) = old.split_at(old.len() / 2);
+    root = tree.inserts(root.as_ref(), old, &leaves[..old.len()])?;
+    let mut sequential = root;
+    for key in gone.iter() {
+        sequential = tree.remove(sequential.as_ref(), key)?;
+    }
+    root = tree.removes(root.as_ref(), gone)?;
+    assert_eq!(root, sequential);
+    assert_eq!(
+        root,
+        tree.inserts(None, left, &leaves[gone.len()..old.len()])?
+    );
+    assert_eq!(tree.remove(root.as_ref(), &gone[0])?, root);
+
+    // new keys, keys removed and keys left, and keys of variable length with duplicates
+    let mut keys: Vec<Vec<u8>> = new.iter().chain(old.iter()).map(|k| k.to_vec()).collect();
+    keys.extend(old.iter().map(|k| k[..k[0] as usize % 8].to_vec()));
+    let leaves = random_hashes(keys.len());
+    for root in [None, root].iter() {
+        let bulk = tree.inserts(root.as_ref(), &keys, &leaves)?;
+        let mut sequential = *root;
+        for i in get_sorted_indices(&keys, false) {
+            sequential = tree.insert(sequential.as_ref(), &keys[i], &leaves[i])?;
+        }
+        assert_eq!(bulk, sequential);
+    }
+    Ok(())
+}
+
+fn insert_keys_then_update_in_single_pass<D: Database, H: Hasher>(
+    mut tree: Monotree<D, H>,
+    _hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()> {
+    let (old, new) = keys.split_at(keys.len() / 2);
+    root = tree.inserts(root.as_ref(), old, &leaves[..old.len()])?;
+
+    // removals only give the same root as removing keys one by one
+    let (removed, kept) = old.split_at(old.len() / 2);
+    let mut sequential = root;
+    for key in removed.iter() {
+        sequential = tree.remove(sequential.as_ref(), key)?;
+    }
+    assert_eq!(tree.removes(root.as_ref(), removed)?, sequential);
+
+    // removals of keys not in the tree leave the tree as it is
+    assert_eq!(tree.removes(root.as_ref(), new)?, root);
+
+    // insert new keys, replace the leaves of the kept and remove the rest, all at once
+    let mut entries: Vec<(Hash, Option<Hash>)> = removed.iter().map(|k| (*k, None)).collect();
+    let replaced = new.iter().chain(kept.iter()).zip(leaves.iter().rev());
+    entries.extend(replaced.map(|(k, v)| (*k, Some(*v))));
+    shuffle(&mut entries);
+    root = tree.update(root.as_ref(), &entries)?;
+    for (key, leaf) in entries.iter() {
+        assert_eq!(tree.get(root.as_ref(), key)?, *leaf);
+    }
+    assert_eq!(tree.iter(root.as_ref()).count(), new.len() + kept.len());
+
+    // the last entry given wins among those with the same key
+    let entries = [(new[0], None), (new[0], Some(leaves[0])), (kept[0], None)];
+    let last = tree.update(root.as_ref(), &entries)?;
+    assert_eq!(tree.get(last.as_ref(), &new[0])?, Some(leaves[0]));
+    assert_eq!(tree.get(last.as_ref(), &kept[0])?, None);
+
+    // removing all the keys leaves nothing
+    let entries: Vec<(Hash, Option<Hash>)> =
+        new.iter().chain(kept.iter()).map(|k| (*k, None)).collect();
+    assert_eq!(tree.update(root.as_ref(), &entries)?, None);
+    Ok(())
+}
+
+fn insert_keys_then_apply_changeset<D: Database, H: Hasher>(
+    mut tree: Monotree<D, H>,
+    _hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()> {
+    let (old, new) = keys.split_at(keys.len() / 2);
+    root = tree.inserts(root.as_ref(), old, &leaves[..old.len()])?;
+    let mut entries: BTreeMap<Vec<u8>, Hash> = BTreeMap::new();
+    for (key, leaf) in old.iter().zip(leaves.iter()) {
+        entries.insert(key.to_vec(), *leaf);
+    }
+
+    // puts and deletes interleaved, some of which undo the others
+    let mut changeset: Vec<Op<Vec<u8>>> = Vec::new();
+    for (i, (key, leaf)) in new
+        .iter()
+        .chain(old.iter())
+        .zip(leaves.iter().rev())
+        .enumerate()
+    {
+        let prefix = key[..key[0] as usize % 4].to_vec();
+        match i % 4 {
+            0 => changeset.push(Op::Put(key.to_vec(), *leaf)),
+            1 => changeset.push(Op::Delete(key.to_vec())),
+            2 => {
+                changeset.push(Op::Put(prefix.clone(), *leaf));
+                changeset.push(Op::Delete(prefix));
+            }
+            _ => {
+                changeset.push(Op::Delete(key.to_vec()));
+                changeset.push(Op::Put(prefix, *leaf));
+            }
+        }
+    }
+    for op in changeset.iter() {
+        match op {
+            Op::Put(key, leaf) => entries.insert(key.to_vec(), *leaf),
+            Op::Delete(key) => entries.remove(key),
+        };
+    }
+    root = tree.apply(root.as_ref(), &changeset)?;
+    let found = tree.iter(root.as_ref()).collect::<Result<Vec<_>>>()?;
+    assert_eq!(found, entries.into_iter().collect::<Vec<_>>());
+
+    // an empty changeset changes nothing
+    assert_eq!(tree.apply::<Hash>(root.as_ref(), &[])?, root);
+    Ok(())
+}
+
+fn insert_keys_then_retain_and_release_roots<D: IterableDatabase, H: Hasher>(
+    mut tree: Monotree<RefCounted<D>, H>,
+    _hasher: &H,
+    mut root: Option<Hash>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()> {
+    // update the tree block by block, retaining the last two roots only
+    let mut roots: Vec<Hash> = Vec::new();
+    for (i, (keys, leaves)) in keys.chunks(20).zip(leaves.chunks(20)).enumerate() {
+        for (key, value) in keys.iter().zip(leaves.iter()) {
+            root = tree.insert(root.as_ref(), key, value)?;
+        }
+        tree.retain(&root.unwrap())?;
+        roots.push(root.unwrap());
+        if i > 1 {
+            tree.release(&roots[i - 2])?;
+        }
+        // the roots retained must be intact, and nothing else must be left
+        assert_eq!(tree.prune(&roots[roots.len().max(2) - 2..])?, 0);
+    }
+    for (key, value) in keys.iter().zip(leaves.iter()) {
+        assert_eq!(tree.get(root.as_ref(), key)?, Some(*value));
+    }
+
+    // remove a half of keys, then release the roots of blocks retained before
+    root = tree.removes(root.as_ref(), &keys[..keys.len() / 2])?;
+    tree.retain(&root.unwrap())?;
+    for root in roots.iter().rev().take(2) {
+        tree.release(root)?;
+    }
+    assert_eq!(tree.prune(&[root.unwrap()])?, 0);
+    for (key, value) in keys.iter().zip(leaves.iter()).skip(keys.len() / 2) {
+        assert_eq!(tree.get(root.as_ref(), key)?, Some(*value));
+    }
+
+    // release the last root: the database must be empty
+    tree.release(&root.unwrap())?;
+    assert_eq!(tree.prune(&[])?, 0);
+    assert!(matches!(
+        tree.release(&root.unwrap()),
+        Err(Error::NotRetained(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_open_fails_on_inaccessible_path() {
+    let dbpath = "/proc/monotree";
+    let tree = Monotree::<RocksDB, Blake3>::open(dbpath);
+    assert!(matches!(tree, Err(Error::Backend(_))));
+    let tree = Monotree::<Sled, Blake3>::open(dbpath);
+    assert!(matches!(tree, Err(Error::Backend(_))));
+    let tree = Monotree::<RefCountedSled, Blake3>::open(dbpath);
+    assert!(matches!(tree, Err(Error::Backend(_))));
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_deprecated_errors_alias() {
+    let err: monotree::Errors = monotree::Errors::new("failed");
+    assert!(matches!(err, Error::Backend(_)));
+    assert_eq!(err.to_string(), "backend: failed");
+}
+
+#[test]
+fn test_build_trees_with_db_opened() -> Result<()> {
+    let dbname = format!(".tmp/{}", hex!(random_bytes(4)));
+    let _g = scopeguard::guard((), |_| {
+        if fs::metadata(&dbname).is_ok() {
+            fs::remove_dir_all(&dbname).unwrap()
+        }
+    });
+    fs::create_dir_all(&dbname).unwrap();
+    let keys = random_hashes(100);
+    let leaves = random_hashes(100);
+    let expected: Vec<Option<Hash>> = leaves.iter().cloned().map(Some).collect();
+
+    // RocksDB with options given
+    let mut opts = rocksdb::Options::default();
+    opts.create_if_missing(true);
+    let db = RawRocksDB::open_with(&format!("{}/rocksdb", dbname), &opts)?;
+    let mut tree = Monotree::<RawRocksDB, Blake3>::with_db(db);
+    let root = tree.inserts(None, &keys, &leaves)?;
+    assert_eq!(tree.gets(root.as_ref(), &keys)?, expected);
+
+    // a sled handle shared by two trees
+    let db = sled::open(format!("{}/sled", dbname))?;
+    let mut tree = Monotree::<RawSled, Blake3>::with_db(RawSled::from_db(db.clone()));
+    let root = tree.inserts(None, &keys, &leaves)?;
+    let other = Monotree::<RawSled, Blake3>::with_db(RawSled::from_db(db));
+    assert_eq!(other.gets(root.as_ref(), &keys)?, expected);
+    Ok(())
+}
+
+#[test]
+fn test_reopen_and_compact_flat_file() -> Result<()> {
+    let dbname = format!(".tmp/{}", hex!(random_bytes(4)));
+    let _g = scopeguard::guard((), |_| {
+        if fs::metadata(&dbname).is_ok() {
+            fs::remove_dir_all(&dbname).unwrap()
+        }
+    });
+    let keys = random_hashes(500);
+    let leaves = random_hashes(500);
+    let expected: Vec<Option<Hash>> = leaves.iter().cloned().map(Some).collect();
+
+    // small segments, so that the records spread over many of them
+    let segments = || fs::read_dir(&dbname).unwrap().count();
+    let mut tree = Monotree::<FlatFile, Blake3>::with_db(FlatFile::open_with(&dbname, 1 << 12)?);
+    let mut root = None;
+    for (keys, leaves) in keys.chunks(50).zip(leaves.chunks(50)) {
+        root = tree.inserts(root.as_ref(), keys, leaves)?;
+    }
+    assert!(segments() > 1);
+
+    // a record left half-written, with lengths past the end or with a checksum not matching
+    // is cut off on open, and the index is rebuilt
+    let last = fs::read_dir(&dbname)?
+        .map(|entry| entry.unwrap().path())
+        .max()
+        .unwrap();
+    let lens = [HASH_LEN as u8, 0, 0, 0, 1, 0, 0, 0];
+    let records = [
+        lens[..5].to_vec(),
+        [&lens[..4], &[0xff, 0xff, 0xff, 0x7f], &[0; 4]].concat(),
+        [&lens[..], &[0; 4], &[0; HASH_LEN + 1]].concat(),
+    ];
+    for record in records.iter() {
+        drop(tree);
+        let len = fs::metadata(&last)?.len();
+        let mut file = fs::OpenOptions::new().append(true).open(&last)?;
+        file.write_all(record)?;
+        tree = Monotree::with_db(FlatFile::open_with(&dbname, 1 << 12)?);
+        assert_eq!(fs::metadata(&last)?.len(), len);
+        assert_eq!(tree.gets(root.as_ref(), &keys)?, expected);
+    }
+
+    // compaction keeps only what is under the roots given
+    let nodes = tree.db().keys()?.count();
+    let dropped = tree.db_mut().compact(&[root.unwrap()])?;
+    assert!(dropped > 0);
+    assert_eq!(tree.db().keys()?.count(), nodes - dropped);
+    assert_eq!(tree.prune(&[root.unwrap()])?, 0);
+    assert_eq!(tree.gets(root.as_ref(), &keys)?, expected);
+
+    // the tree goes on in the compacted segments, also after reopening them
+    root = tree.removes(root.as_ref(), &keys[..250])?;
+    drop(tree);
+    let tree = Monotree::<FlatFile, Blake3>::open(&dbname)?;
+    assert_eq!(
+        tree.gets(root.as_ref(), &keys[250..])?,
+        expected[250..].to_vec()
+    );
+    assert_eq!(tree.get(root.as_ref(), &keys[0])?, None);
+    Ok(())
+}
+
+#[test]
+fn test_keep_flat_file_on_failed_compaction() -> Result<()> {
+    let dbname = format!(".tmp/{}", hex!(random_bytes(4)));
+    let _g = scopeguard::guard((), |_| {
+        if fs::metadata(&dbname).is_ok() {
+            fs::remove_dir_all(&dbname).unwrap()
+        }
+    });
+    let keys = random_hashes(100);
+    let leaves = random_hashes(100);
+    let expected: Vec<Option<Hash>> = leaves.iter().cloned().map(Some).collect();
+    let mut tree = Monotree::<FlatFile, Blake3>::with_db(FlatFile::open_with(&dbname, 1 << 12)?);
+    let root = tree.inserts(None, &keys, &leaves)?;
+
+    // a record cut off from under the flat file, after the nodes
+    let last = fs::read_dir(&dbname)?
+        .map(|entry| entry.unwrap().path())
+        .max()
+        .unwrap();
+    let len = fs::metadata(&last)?.len();
+    tree.db_mut().put(b"value", vec![0x00; 1 << 12])?;
+    fs::OpenOptions::new()
+        .write(true)
+        .open(&last)?
+        .set_len(len)?;
+
+    // compaction fails on copying it, and the old segments are still read from
+    let nodes = tree.db().keys()?.count();
+    assert!(tree.db_mut().compact(&[root.unwrap()]).is_err());
+    assert_eq!(tree.db().keys()?.count(), nodes);
+    assert_eq!(tree.gets(root.as_ref(), &keys)?, expected);
+    Ok(())
+}
+
+fn update_keys_block_by_block<D: Database>(
+    tree: &mut Monotree<RefCounted<D>, Blake3>,
+    keys: &[Hash],
+    leaves: &[Hash],
+) -> Result<()> {
+    let mut roots: Vec<Hash> = Vec::new();
+    let mut root = None;
+    for (keys, leaves) in keys.chunks(50).zip(leaves.chunks(50)) {
+        root = tree.inserts(root.as_ref(), keys, leaves)?;
+        tree.retain(&root.unwrap())?;
+        roots.push(root.unwrap());
+    }
+    root = tree.removes(root.as_ref(), &keys[..keys.len() / 2])?;
+    tree.retain(&root.unwrap())?;
+    for root in roots.iter() {
+        tree.release(root)?;
+    }
+    let (removed, kept) = keys.split_at(keys.len() / 2);
+    for key in removed.iter() {
+        assert_eq!(tree.get(root.as_ref(), key)?, None);
+    }
+    let expected: Vec<Option<Hash>> = leaves[removed.len()..].iter().cloned().map(Some).collect();
+    assert_eq!(tree.gets(root.as_ref(), kept)?, expected);
+    Ok(())
+}
+
+#[test]
+fn test_flat_file_rolls_back_abandoned_batch() -> Result<()> {
+    let dbname = format!(".tmp/{}", hex!(random_bytes(4)));
+    let _g = scopeguard::guard((), |_| {
+        if fs::metadata(&dbname).is_ok() {
+            fs::remove_dir_all(&dbname).unwrap()
+        }
+    });
+    let mut db = FlatFile::open(&dbname)?;
+    let (kept, dropped) = (random_bytes(32), random_bytes(32));
+    db.put(&kept, vec![0x01])?;
+    db.init_batch()?;
+    db.put(&dropped, vec![0x02])?;
+    db.put(&kept, vec![0x03])?;
+    db.delete(&kept)?;
+    // the next batch begins afresh, without the writes left behind
+    db.init_batch()?;
+    db.finish_batch()?;
+    assert_eq!(db.get(&dropped)?, None);
+    assert_eq!(db.get(&kept)?, Some(vec![0x01]));
+
+    // nor are they written to the segments
+    drop(db);
+    let db = FlatFile::open(&dbname)?;
+    assert_eq!(db.get(&dropped)?, None);
+    assert_eq!(db.get(&kept)?, Some(vec![0x01]));
+    Ok(())
+}
+
+#[test]
+fn test_update_trees_with_small_caches() -> Result<()> {
+    let dbname = format!(".tmp/{}", hex!(random_bytes(4)));
+    let _g = scopeguard::guard((), |_| {
+        if fs::metadata(&dbname).is_ok() {
+            fs::remove_dir_all(&dbname).unwrap()
+        }
+    });
+    fs::create_dir_all(&dbname).unwrap();
+    let keys = random_hashes(500);
+    let leaves = random_hashes(500);
+
+    // the caches evict all the time, but never what is written in the batch in progress
+    let db = CachedDb::from_db(MemoryDB::new("")).with_cache_size(1 << 10);
+    update_keys_block_by_block(
+        &mut Monotree::with_db(RefCounted::from_db(db)),
+        &keys,
+        &leaves,
+    )?;
+    let db = RocksDB::open(&format!("{}/rocksdb", dbname))?.with_cache_size(1 << 10);
+    update_keys_block_by_block(
+        &mut Monotree::with_db(RefCounted::from_db(db)),
+        &keys,
+        &leaves,
+    )?;
+    let db = Sled::open(&format!("{}/sled", dbname))?.with_cache_size(1 << 10);
+    update_keys_block_by_block(
+        &mut Monotree::with_db(RefCounted::from_db(db)),
+        &keys,
+        &leaves,
+    )
+}
+
+#[test]
+fn test_cache_kept_across_batches() -> Result<()> {
+    let mut tree = Monotree::<CachedDb<MemoryDB>, Blake3>::new("");
+    let keys = random_hashes(100);
+    let leaves = random_hashes(100);
+    let root = tree.inserts(None, &keys[..50], &leaves[..50])?;
+    tree.gets(root.as_ref(), &keys[..50])?;
+    assert_eq!(tree.db().cache_stats().misses, 0);
+
+    // the nodes read before the batch are still in the cache after it
+    let next = tree.inserts(root.as_ref(), &keys[50..], &leaves[50..])?;
+    let stats = tree.db().cache_stats();
+    tree.gets(root.as_ref(), &keys[..50])?;
+    tree.gets(next.as_ref(), &keys)?;
+    assert_eq!(tree.db().cache_stats().misses, stats.misses);
+    assert!(tree.db().cache_stats().hits > stats.hits);
+    Ok(())
+}
+
+#[test]
+fn test_cache_drops_abandoned_batch() -> Result<()> {
+    let mut db = CachedDb::from_db(BatchingDb::from_db(MemoryDB::new("")));
+    let (kept, dropped) = (random_bytes(32), random_bytes(32));
+    db.put(&kept, vec![0x01])?;
+
+    // a batch left unfinished, as by an error on the way, is never written
+    db.init_batch()?;
+    db.put(&dropped, vec![0x02])?;
+    db.delete(&kept)?;
+    db.init_batch()?;
+    db.finish_batch()?;
+    assert_eq!(db.get(&dropped)?, None);
+    assert_eq!(db.get(&kept)?, Some(vec![0x01]));
+    Ok(())
+}
+
+#[cfg(feature = "lmdb")]
+#[test]
+fn test_lmdb_reads_what_is_committed() -> Result<()> {
+    let dbname = format!(".tmp/{}", hex!(random_bytes(4)));
+    let _g = scopeguard::guard((), |_| {
+        if fs::metadata(&dbname).is_ok() {
+            fs::remove_dir_all(&dbname).unwrap()
+        }
+    });
+    let mut db = Lmdb::open(&dbname)?;
+    let reader = db.share()?;
+    let (key, other) = (random_bytes(32), random_bytes(32));
+    db.put(&key, vec![0x01])?;
+    assert_eq!(reader.get(&key)?, Some(vec![0x01]));
+
+    // each read sees what is committed by the others at the time
+    db.delete(&key)?;
+    db.put(&other, vec![0x02])?;
+    assert_eq!(reader.get(&key)?, None);
+    assert_eq!(reader.get(&other)?, Some(vec![0x02]));
+
+    // the writer reads its own writes
+    assert_eq!(db.get(&key)?, None);
+    assert_eq!(db.get(&other)?, Some(vec![0x02]));
+    Ok(())
+}
+
+#[cfg(feature = "rusqlite")]
+#[test]
+fn test_sqlite_rolls_back_abandoned_batch() -> Result<()> {
+    let dbname = format!(".tmp/{}.sqlite", hex!(random_bytes(4)));
+    let _g = scopeguard::guard((), |_| {
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", dbname, suffix));
+        }
+    });
+    let mut db = Sqlite::open(&dbname)?;
+    let (kept, dropped) = (random_bytes(32), random_bytes(32));
+    db.put(&kept, vec![0x01])?;
+    db.init_batch()?;
+    db.put(&dropped, vec![0x02])?;
+    db.delete(&kept)?;
+
+    // the next batch begins afresh, without the writes left behind
+    db.init_batch()?;
+    db.finish_batch()?;
+    assert_eq!(db.get(&dropped)?, None);
+    assert_eq!(db.get(&kept)?, Some(vec![0x01]));
+    Ok(())
+}
+
+#[cfg(feature = "rusqlite")]
+#[test]
+fn test_sqlite_in_memory_not_shared() -> Result<()> {
+    let tree = Monotree::<CachedSqlite, Blake3>::open(":memory:")?;
+    assert!(matches!(
+        SharedMonotree::from_tree(tree),
+        Err(Error::Backend(_))
+    ));
+    Ok(())
+}
+
 macro_rules! impl_integration_test {
     ($fn:ident, ($d:expr, $db:ident), ($h:expr, $hasher:ident), $n:expr) => {
         paste::item_with_macros! {
@@ -182,8 +1236,10 @@ macro_rules! impl_integration_test {
             fn [<test_ $d _ $h _ $fn _ $n>]() -> Result<()> {
                 let dbname = format!(".tmp/{}", hex!(random_bytes(4)));
                 let _g = scopeguard::guard((), |_| {
-                    if fs::metadata(&dbname).is_ok() {
-                        fs::remove_dir_all(&dbname).unwrap()
+                    match fs::metadata(&dbname) {
+                        Ok(meta) if meta.is_dir() => fs::remove_dir_all(&dbname).unwrap(),
+                        Ok(_) => fs::remove_file(&dbname).unwrap(),
+                        Err(_) => {}
                     }
                 });
                 let keys = random_hashes($n);
@@ -230,12 +1286,109 @@ impl_test_with_params!(
     [
         insert_keys_then_verify_values,
         insert_keys_then_gen_and_verify_proof,
+        insert_keys_then_gen_and_verify_non_inclusion_proof,
         insert_keys_then_delete_keys_immediately,
         insert_keys_then_delete_keys_in_order,
         insert_keys_then_delete_keys_reversely,
-        insert_keys_then_delete_keys_randomly
+        insert_keys_then_delete_keys_randomly,
+        insert_keys_then_prune_unreachable_nodes,
+        insert_values_then_get_values_and_verify_proof,
+        insert_variable_length_keys_then_delete_keys,
+        insert_keys_then_iterate_in_order,
+        insert_keys_then_query_range_and_prefix,
+        insert_keys_then_gen_and_verify_range_proof,
+        insert_keys_then_gen_and_verify_multi_proof,
+        insert_keys_then_encode_and_decode_proof,
+        insert_keys_then_read_from_threads,
+        insert_keys_in_bulk_then_compare_roots,
+        insert_keys_then_update_in_single_pass,
+        insert_keys_then_apply_changeset
+    ],
+    [
+        ("hashmap", MemoryDB),
+        ("rocksdb", RocksDB),
+        ("sled", Sled),
+        ("flatfile", CachedFlatFile)
+    ],
+    [
+        ("blake3", Blake3),
+        ("blake2s", Blake2s),
+        ("blake2b", Blake2b),
+        ("sha2", Sha2),
+        ("sha3", Sha3)
+    ],
+    [100, 500, 1000]
+);
+
+impl_test_with_params!(
+    [insert_keys_then_retain_and_release_roots],
+    [
+        ("refcounted_hashmap", RefCountedMemoryDB),
+        ("refcounted_rocksdb", RefCountedRocksDB),
+        ("refcounted_sled", RefCountedSled),
+        ("refcounted_flatfile", RefCountedFlatFile)
+    ],
+    [
+        ("blake3", Blake3),
+        ("blake2s", Blake2s),
+        ("blake2b", Blake2b),
+        ("sha2", Sha2),
+        ("sha3", Sha3)
+    ],
+    [100, 500, 1000]
+);
+
+impl_test_with_params!(
+    [insert_keys_then_read_while_writing],
+    [
+        ("rocksdb", RocksDB),
+        ("sled", Sled),
+        ("refcounted_sled", RefCountedSled)
+    ],
+    [
+        ("blake3", Blake3),
+        ("blake2s", Blake2s),
+        ("blake2b", Blake2b),
+        ("sha2", Sha2),
+        ("sha3", Sha3)
+    ],
+    [100, 500, 1000]
+);
+
+#[cfg(feature = "lmdb")]
+impl_test_with_params!(
+    [
+        insert_keys_then_verify_values,
+        insert_keys_then_gen_and_verify_proof,
+        insert_keys_then_delete_keys_randomly,
+        insert_keys_then_iterate_in_order,
+        insert_keys_in_bulk_then_compare_roots,
+        insert_keys_then_apply_changeset,
+        insert_keys_then_read_while_writing
+    ],
+    [("lmdb", CachedLmdb)],
+    [
+        ("blake3", Blake3),
+        ("blake2s", Blake2s),
+        ("blake2b", Blake2b),
+        ("sha2", Sha2),
+        ("sha3", Sha3)
+    ],
+    [100, 500, 1000]
+);
+
+#[cfg(feature = "rusqlite")]
+impl_test_with_params!(
+    [
+        insert_keys_then_verify_values,
+        insert_keys_then_gen_and_verify_proof,
+        insert_keys_then_delete_keys_randomly,
+        insert_keys_then_iterate_in_order,
+        insert_keys_in_bulk_then_compare_roots,
+        insert_keys_then_apply_changeset,
+        insert_keys_then_read_while_writing
     ],
-    [("hashmap", MemoryDB), ("rocksdb", RocksDB), ("sled", Sled)],
+    [("sqlite", CachedSqlite)],
     [
         ("blake3", Blake3),
         ("blake2s", Blake2s),
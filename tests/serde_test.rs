@@ -0,0 +1,101 @@
+#![cfg(feature = "serde")]
+use monotree::utils::random_hashes;
+use monotree::{Hash, Monotree, NonInclusionProof, OwnedNode, Proof, RangeProof, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Response {
+    #[serde(with = "monotree::serdes::option_hash")]
+    root: Option<Hash>,
+    proof: Proof,
+    absence: NonInclusionProof,
+    #[serde(with = "monotree::serdes::nodes")]
+    range: RangeProof,
+    node: OwnedNode,
+}
+
+fn response() -> Result<Response> {
+    let mut tree = Monotree::default();
+    let mut keys = random_hashes(100);
+    let leaves = random_hashes(100);
+    let root = tree.inserts(None, &keys[1..], &leaves[1..])?;
+    let proof = tree.get_merkle_proof(root.as_ref(), &keys[1])?.unwrap();
+    let absence = tree
+        .get_non_inclusion_proof(root.as_ref(), &keys[0])?
+        .unwrap();
+    keys.sort();
+    let range = tree.get_range_proof(root.as_ref(), &keys[10], &keys[20])?;
+    let node = OwnedNode::from_bytes(&absence.node)?;
+    Ok(Response {
+        root,
+        proof,
+        absence,
+        range,
+        node,
+    })
+}
+
+#[test]
+fn test_serde_json() -> Result<()> {
+    let response = response()?;
+    let json = serde_json::to_string(&response).unwrap();
+    assert_eq!(serde_json::from_str::<Response>(&json).unwrap(), response);
+
+    // hashes written in hex
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    let root = hex::encode(response.root.unwrap());
+    assert_eq!(value["root"], serde_json::Value::String(root));
+    let cut = hex::encode(&response.proof[0].1);
+    assert_eq!(value["proof"][0][1], serde_json::Value::String(cut));
+
+    // the node bytes unchanged through the owned node
+    assert_eq!(response.node.to_bytes()?, response.absence.node);
+    Ok(())
+}
+
+#[test]
+fn test_serde_binary() -> Result<()> {
+    let response = response()?;
+    let bytes = bincode::serialize(&response).unwrap();
+    assert_eq!(bincode::deserialize::<Response>(&bytes).unwrap(), response);
+
+    // a proof serialized in binary is the same as encoded
+    let bytes = bincode::serialize(&response.proof).unwrap();
+    assert!(bytes.ends_with(&response.proof.encode()?));
+    Ok(())
+}
+
+#[test]
+fn test_serde_rejects_malformed_proof() {
+    let json = r#"[[false, "00"]]"#;
+    assert!(serde_json::from_str::<Proof>(json).is_err());
+    let json = r#"[[false, "zz"]]"#;
+    assert!(serde_json::from_str::<Proof>(json).is_err());
+    let bytes = bincode::serialize(&vec![0x01u8, 0x00, 0x01]).unwrap();
+    assert!(bincode::deserialize::<Proof>(&bytes).is_err());
+}
+
+#[test]
+fn test_serde_rejects_malformed_node() {
+    let hash = hex::encode([0u8; 32]);
+    let unit = |path: &str, start: u16, end: u16| {
+        format!(
+            r#"{{"hash": "{}", "bits": {{"path": "{}", "range": {{"start": {}, "end": {}}}}}}}"#,
+            hash, path, start, end
+        )
+    };
+    let json = format!(r#"{{"Soft": {}}}"#, unit("ff", 1, 8));
+    assert!(serde_json::from_str::<OwnedNode>(&json).is_ok());
+
+    // ranges out of the path, empty or starting past the first byte
+    for &(path, start, end) in &[("ff", 1, 9), ("ff", 3, 3), ("ff", 5, 2), ("ffff", 8, 16)] {
+        let json = format!(r#"{{"Soft": {}}}"#, unit(path, start, end));
+        assert!(serde_json::from_str::<OwnedNode>(&json).is_err());
+    }
+
+    // cells left empty
+    assert!(serde_json::from_str::<OwnedNode>(r#"{"Soft": null}"#).is_err());
+    let json = format!(r#"{{"Hard": [{}, null]}}"#, unit("ff", 0, 8));
+    assert!(serde_json::from_str::<OwnedNode>(&json).is_err());
+    assert!(serde_json::from_str::<OwnedNode>(r#"{"Hard": [null, null]}"#).is_err());
+}